@@ -0,0 +1,81 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use segarray::SegArray;
+
+const LEN: usize = 1 << 20;
+
+fn filled() -> SegArray<u64> {
+    filled_to(LEN)
+}
+
+fn filled_to(len: usize) -> SegArray<u64> {
+    let mut arr = SegArray::new();
+    for i in 0..len as u64 {
+        arr.append(i);
+    }
+    arr
+}
+
+fn append(c: &mut Criterion) {
+    c.bench_function("append 1M", |b| b.iter(|| black_box(filled())));
+}
+
+fn index(c: &mut Criterion) {
+    let arr = filled();
+    c.bench_function("index 1M", |b| {
+        b.iter(|| {
+            let mut sum = 0u64;
+            for i in 0..LEN {
+                sum = sum.wrapping_add(arr[black_box(i)]);
+            }
+            sum
+        })
+    });
+    c.bench_function("get 1M", |b| {
+        b.iter(|| {
+            let mut sum = 0u64;
+            for i in 0..LEN {
+                sum = sum.wrapping_add(*arr.get(black_box(i)).unwrap());
+            }
+            sum
+        })
+    });
+}
+
+fn iterate(c: &mut Criterion) {
+    let arr = filled();
+    c.bench_function("iter 1M", |b| {
+        b.iter(|| arr.iter().fold(0u64, |sum, &x| sum.wrapping_add(x)))
+    });
+}
+
+fn for_each(c: &mut Criterion) {
+    let arr = filled();
+    c.bench_function("for_each 1M", |b| {
+        b.iter(|| {
+            let mut sum = 0u64;
+            arr.for_each(|&x| sum = sum.wrapping_add(x));
+            sum
+        })
+    });
+    c.bench_function("index loop 1M", |b| {
+        b.iter(|| {
+            let mut sum = 0u64;
+            for i in 0..arr.len() {
+                sum = sum.wrapping_add(arr[i]);
+            }
+            sum
+        })
+    });
+}
+
+fn drop_array(c: &mut Criterion) {
+    // `u64` needs no drop, so this measures freeing the segments and nothing else
+    c.bench_function("drop 10M", |b| {
+        b.iter_batched(|| filled_to(10_000_000), drop, BatchSize::PerIteration)
+    });
+}
+
+criterion_group!(benches, append, index, iterate, for_each, drop_array);
+criterion_main!(benches);
@@ -0,0 +1,238 @@
+// The arithmetic mapping logical indices onto segments. Segment `i` holds `2^i` elements and
+// starts at logical index `2^i - 1`, so every index can be located with a couple of bit
+// operations.
+//
+// The `capped_*` variants stop the doubling at segment `max_shift`: every later segment holds
+// `2^max_shift` elements too. With `max_shift` at `UNCAPPED` they match the plain versions.
+
+/// A `max_shift` no segment table is long enough to reach, so sizes never stop doubling.
+pub(crate) const UNCAPPED: usize = usize::BITS as usize - 1;
+
+/// The segment holding logical index `index`.
+pub(crate) fn segment_index(index: usize) -> usize {
+    checked_segment_index(index).expect("usize::MAX has no segment")
+}
+
+/// Like [`segment_index`], but `None` for `usize::MAX`, whose segment isn't representable.
+pub(crate) fn checked_segment_index(index: usize) -> Option<usize> {
+    let next = index.checked_add(1)?;
+    Some(next.ilog2().try_into().unwrap())
+}
+
+/// The offset of logical index `index` within its segment `segment_index`.
+pub(crate) fn segment_slot(index: usize, segment_index: usize) -> usize {
+    index + 1 - (1 << segment_index)
+}
+
+/// The logical index of the first element in segment `segment_index`.
+pub(crate) fn segment_start(segment_index: usize) -> usize {
+    (1 << segment_index) - 1
+}
+
+/// The number of segments needed to hold `capacity` elements.
+pub(crate) fn segment_count_for_capacity(capacity: usize) -> usize {
+    match capacity.checked_add(1) {
+        Some(x) => ilog2_ceil(x),
+        // `usize::MAX` is exactly the combined size of the first `usize::BITS` segments
+        None => usize::BITS as usize,
+    }
+}
+
+// The number of elements in the doubling segments `0..=max_shift`, i.e. 2^(max_shift + 1) - 1
+fn doubling_len(max_shift: usize) -> usize {
+    usize::MAX >> (UNCAPPED - max_shift)
+}
+
+/// The size of segment `segment_index`.
+pub(crate) fn capped_segment_len(segment_index: usize, max_shift: usize) -> usize {
+    1 << segment_index.min(max_shift)
+}
+
+pub(crate) fn capped_segment_index(index: usize, max_shift: usize) -> usize {
+    let doubling = doubling_len(max_shift);
+    if index < doubling {
+        segment_index(index)
+    } else {
+        max_shift + 1 + ((index - doubling) >> max_shift)
+    }
+}
+
+pub(crate) fn capped_segment_start(segment_index: usize, max_shift: usize) -> usize {
+    if segment_index <= max_shift {
+        segment_start(segment_index)
+    } else {
+        doubling_len(max_shift) + ((segment_index - max_shift - 1) << max_shift)
+    }
+}
+
+pub(crate) fn capped_segment_slot(index: usize, segment_index: usize, max_shift: usize) -> usize {
+    if segment_index <= max_shift {
+        segment_slot(index, segment_index)
+    } else {
+        index - capped_segment_start(segment_index, max_shift)
+    }
+}
+
+pub(crate) fn capped_segment_count_for_capacity(capacity: usize, max_shift: usize) -> usize {
+    let doubling = doubling_len(max_shift);
+    if capacity <= doubling {
+        segment_count_for_capacity(capacity)
+    } else {
+        max_shift + 1 + (capacity - doubling).div_ceil(1 << max_shift)
+    }
+}
+
+/// The combined size of segments `[start, end)`.
+pub(crate) fn segments_len(start: usize, end: usize, max_shift: usize) -> usize {
+    (start..end).map(|i| capped_segment_len(i, max_shift)).sum()
+}
+
+pub(crate) fn ilog2_ceil(x: usize) -> usize {
+    assert!(x != 0);
+    let l2 = x.ilog2();
+    if 1 << l2 == x {
+        l2.try_into().unwrap()
+    } else {
+        (l2 + 1).try_into().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every index below 2^16, plus the neighbourhood of each larger power of two
+    fn sample_indices() -> impl Iterator<Item = usize> {
+        let boundaries = (16..usize::BITS).flat_map(|bit| {
+            let power = 1usize << bit;
+            [power - 2, power - 1, power, power + 1]
+        });
+        (0..1 << 16)
+            .chain(boundaries)
+            .chain([usize::MAX - 2, usize::MAX - 1])
+    }
+
+    #[test]
+    fn test_slot_is_within_segment() {
+        for index in sample_indices() {
+            let seg = segment_index(index);
+            let slot = segment_slot(index, seg);
+            assert!(
+                slot < 1 << seg,
+                "index {index}: slot {slot} in segment {seg}"
+            );
+            assert_eq!(segment_start(seg) + slot, index);
+        }
+    }
+
+    #[test]
+    fn test_segment_count_covers_index() {
+        for index in sample_indices() {
+            // Holding `index + 1` elements needs exactly the segments up to and including the one
+            // containing `index`
+            assert_eq!(
+                segment_count_for_capacity(index + 1),
+                segment_index(index) + 1
+            );
+        }
+        assert_eq!(segment_count_for_capacity(0), 0);
+    }
+
+    #[test]
+    fn test_segment_count_near_usize_max() {
+        let bits = usize::BITS as usize;
+        assert_eq!(segment_count_for_capacity(usize::MAX), bits);
+        assert_eq!(segment_count_for_capacity(usize::MAX - 1), bits);
+        assert_eq!(segment_count_for_capacity(usize::MAX >> 1), bits - 1);
+        assert_eq!(segment_count_for_capacity((usize::MAX >> 1) + 1), bits);
+    }
+
+    #[test]
+    fn test_segment_count_at_power_of_two_boundaries() {
+        for bit in 2..usize::BITS as usize {
+            // The first `bit` segments hold exactly 2^bit - 1 elements, one more needs another
+            let full = (1usize << bit) - 1;
+            assert_eq!(segment_count_for_capacity(full - 1), bit);
+            assert_eq!(segment_count_for_capacity(full), bit);
+            assert_eq!(segment_count_for_capacity(full + 1), bit + 1);
+        }
+    }
+
+    #[test]
+    fn test_checked_segment_index_edges() {
+        assert_eq!(checked_segment_index(0), Some(0));
+        assert_eq!(checked_segment_index(6), Some(2));
+        assert_eq!(
+            checked_segment_index(usize::MAX - 1),
+            Some(usize::BITS as usize - 1)
+        );
+        assert_eq!(checked_segment_index(usize::MAX), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "usize::MAX has no segment")]
+    fn test_segment_index_of_max_panics() {
+        segment_index(usize::MAX);
+    }
+
+    #[test]
+    fn test_segments_len_matches_starts() {
+        for end in 0..usize::BITS as usize {
+            assert_eq!(segments_len(0, end, UNCAPPED), segment_start(end));
+        }
+        assert_eq!(segments_len(3, 6, UNCAPPED), 8 + 16 + 32);
+        assert_eq!(segments_len(4, 4, UNCAPPED), 0);
+        assert_eq!(segments_len(3, 6, 4), 8 + 16 + 16);
+        assert_eq!(segments_len(0, 9, 2), capped_segment_start(9, 2));
+    }
+
+    #[test]
+    fn test_uncapped_matches_doubling() {
+        for index in sample_indices() {
+            let seg = segment_index(index);
+            assert_eq!(capped_segment_index(index, UNCAPPED), seg);
+            assert_eq!(
+                capped_segment_slot(index, seg, UNCAPPED),
+                segment_slot(index, seg)
+            );
+            assert_eq!(
+                capped_segment_count_for_capacity(index, UNCAPPED),
+                segment_count_for_capacity(index)
+            );
+        }
+    }
+
+    #[test]
+    fn test_capped_slot_is_within_segment() {
+        for max_shift in [0, 1, 3, 10] {
+            for index in 0..1 << 14 {
+                let seg = capped_segment_index(index, max_shift);
+                let slot = capped_segment_slot(index, seg, max_shift);
+                assert!(slot < capped_segment_len(seg, max_shift));
+                assert_eq!(capped_segment_start(seg, max_shift) + slot, index);
+                assert_eq!(
+                    capped_segment_start(seg + 1, max_shift),
+                    capped_segment_start(seg, max_shift) + capped_segment_len(seg, max_shift)
+                );
+                assert_eq!(
+                    capped_segment_count_for_capacity(index + 1, max_shift),
+                    seg + 1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_capped_sizes() {
+        // 1, 2, 4, 8, then 8s
+        assert_eq!(capped_segment_index(14, 3), 3);
+        assert_eq!(capped_segment_index(15, 3), 4);
+        assert_eq!(capped_segment_index(22, 3), 4);
+        assert_eq!(capped_segment_index(23, 3), 5);
+        assert_eq!(capped_segment_start(5, 3), 23);
+        assert_eq!(capped_segment_count_for_capacity(15, 3), 4);
+        assert_eq!(capped_segment_count_for_capacity(16, 3), 5);
+        assert_eq!(capped_segment_count_for_capacity(23, 3), 5);
+        assert_eq!(capped_segment_count_for_capacity(24, 3), 6);
+    }
+}
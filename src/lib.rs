@@ -1,16 +1,67 @@
 use std::{
     alloc::Layout,
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    fmt, io,
     marker::PhantomData,
-    mem::ManuallyDrop,
-    ops::{Index, IndexMut},
+    mem::{ManuallyDrop, MaybeUninit},
+    ops::{Bound, Index, IndexMut, Range, RangeBounds},
 };
 
-#[derive(Debug, Clone)]
+mod layout;
+
+const MAX_SEGMENTS: usize = 32;
+
+// The combined size of all `MAX_SEGMENTS` segments, i.e. 2^MAX_SEGMENTS - 1
+const MAX_CAPACITY: usize = usize::MAX >> (usize::BITS as usize - MAX_SEGMENTS);
+
+// The smallest cap `with_max_segment_size` accepts. Anything lower leaves the 32 segments holding
+// fewer than 65,536 elements between them.
+const MIN_MAX_SEGMENT_SIZE: usize = 1 << 12;
+
+/// The pieces of a [`SegArray`]: `(segments, segment_usage, allocated_segments, count)`.
+pub type RawParts<T> = ([*mut T; MAX_SEGMENTS], [usize; MAX_SEGMENTS], usize, usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegArrayError {
+    /// The requested capacity exceeds what a `SegArray` can hold.
+    CapacityOverflow,
+    /// The allocator failed to provide a segment with this layout.
+    AllocError { layout: Layout },
+    /// `index` was not less than the array's length `len`.
+    IndexOutOfBounds { index: usize, len: usize },
+}
+
+impl fmt::Display for SegArrayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SegArrayError::CapacityOverflow => write!(f, "capacity overflow"),
+            SegArrayError::AllocError { layout } => {
+                write!(f, "failed to allocate a segment of {} bytes", layout.size())
+            }
+            SegArrayError::IndexOutOfBounds { index, len } => {
+                write!(f, "index {index} is out of bounds for length {len}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SegArrayError {}
+
+#[derive(Debug)]
 pub struct SegArray<T> {
     count: usize,
     allocated_segments: usize,
-    segments: [*mut T; 32],
-    segment_usage: [usize; 32],
+    segments: [*mut T; MAX_SEGMENTS],
+    segment_usage: [usize; MAX_SEGMENTS],
+    // Whether each segment is the first in its allocation. Segments are usually allocated one at a
+    // time, but `reserve_contiguous` places several in a single buffer.
+    allocation_starts: [bool; MAX_SEGMENTS],
+    // Unused segments kept around by `defragment` for `grow` to reuse, indexed like `segments`.
+    // The pool always sits directly above the allocated segments.
+    free_segments: [*mut T; MAX_SEGMENTS],
+    // Segments stop doubling at `2^max_segment_shift` elements, see `with_max_segment_size`
+    max_segment_shift: usize,
     _marker: PhantomData<T>,
 }
 
@@ -20,17 +71,133 @@ impl<T> Default for SegArray<T> {
     }
 }
 
+// `SegArray` owns its `T`s just like `Vec` does, so the raw segment pointers don't make it any
+// less thread-safe than `T` itself
+unsafe impl<T: Send> Send for SegArray<T> {}
+unsafe impl<T: Sync> Sync for SegArray<T> {}
+
 impl<T> SegArray<T> {
-    pub fn new() -> Self {
+    pub const EMPTY: Self = Self::new();
+
+    pub const fn new() -> Self {
         Self {
             count: 0,
             allocated_segments: 0,
-            segments: [std::ptr::null_mut(); 32],
-            segment_usage: [0; 32],
+            segments: [std::ptr::null_mut(); MAX_SEGMENTS],
+            segment_usage: [0; MAX_SEGMENTS],
+            allocation_starts: [false; MAX_SEGMENTS],
+            free_segments: [std::ptr::null_mut(); MAX_SEGMENTS],
+            max_segment_shift: layout::UNCAPPED,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Creates an empty array whose segments stop doubling once they reach `max_segment_size`
+    /// elements; every later segment has that size too. This bounds how much a single growth
+    /// step allocates, at the cost of a much lower maximum capacity since the segment table
+    /// still has only 32 entries.
+    ///
+    /// For `max_segment_size = 2^s` the array holds at most `2^s * (33 - s) - 1` elements: 86,015
+    /// for the smallest accepted size of 4096, about 13.6 million for 2^20, and the usual
+    /// 2^32 - 1 once `s` reaches 31.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `max_segment_size` is a power of two of at least 4096.
+    pub fn with_max_segment_size(max_segment_size: usize) -> Self {
+        assert!(
+            max_segment_size.is_power_of_two(),
+            "Maximum segment size {max_segment_size} is not a power of two"
+        );
+        assert!(
+            max_segment_size >= MIN_MAX_SEGMENT_SIZE,
+            "Maximum segment size {max_segment_size} is below the minimum of {MIN_MAX_SEGMENT_SIZE}"
+        );
+        let mut arr = Self::new();
+        arr.max_segment_shift = max_segment_size.trailing_zeros() as usize;
+        arr
+    }
+
+    /// Creates an empty array with the first `segments` segments already allocated.
+    pub fn with_segments(segments: usize) -> Self {
+        assert!(
+            segments <= MAX_SEGMENTS,
+            "Cannot allocate {segments} segments, the maximum is {MAX_SEGMENTS}"
+        );
+        let mut arr = Self::new();
+        let capacity = layout::segments_len(0, segments, layout::UNCAPPED);
+        if let Err(e) = arr.grow(capacity) {
+            Self::grow_failed(e)
+        }
+        arr
+    }
+
+    /// Creates a `SegArray` that takes ownership of existing segment buffers.
+    ///
+    /// # Safety
+    ///
+    /// - For every `i < allocated_segments`, `segments[i]` must have been allocated by the global
+    ///   allocator as its own allocation with the layout `Layout::array::<T>(1 << i)`, and must
+    ///   not be used or freed by anything else afterwards. Every later entry must be null.
+    /// - `allocated_segments` must be at most 32 and leave room for `count` elements, i.e.
+    ///   `count < 2^allocated_segments`.
+    /// - The first `count` logical elements must be initialized: segments are filled in order,
+    ///   so every segment before the one holding element `count - 1` is completely full.
+    /// - `segment_usage[i]` must be the number of those elements stored in segment `i`, which is
+    ///   0 for every segment past the last populated one.
+    pub unsafe fn from_raw_parts(
+        segments: [*mut T; MAX_SEGMENTS],
+        segment_usage: [usize; MAX_SEGMENTS],
+        allocated_segments: usize,
+        count: usize,
+    ) -> Self {
+        let mut allocation_starts = [false; MAX_SEGMENTS];
+        allocation_starts[..allocated_segments].fill(true);
+        Self {
+            count,
+            allocated_segments,
+            segments,
+            segment_usage,
+            allocation_starts,
+            free_segments: [std::ptr::null_mut(); MAX_SEGMENTS],
+            max_segment_shift: layout::UNCAPPED,
             _marker: PhantomData,
         }
     }
 
+    /// Disassembles the array into `(segments, segment_usage, allocated_segments, count)`
+    /// without dropping any elements or freeing any segments. The parts satisfy the requirements
+    /// of [`SegArray::from_raw_parts`], so they can be put back together later.
+    ///
+    /// Segments pooled by [`SegArray::defragment`] are freed first.
+    ///
+    /// # Panics
+    ///
+    /// The parts can't express either of these, so this panics
+    /// - if some segments share one allocation (see [`SegArray::reserve_contiguous`]), or
+    /// - for capped arrays, whose segment size is limited by
+    ///   [`SegArray::with_max_segment_size`].
+    pub fn into_raw_parts(self) -> RawParts<T> {
+        assert!(
+            self.max_segment_shift == layout::UNCAPPED,
+            "Cannot disassemble a SegArray with a capped segment size"
+        );
+        assert!(
+            self.allocation_starts[..self.allocated_segments]
+                .iter()
+                .all(|&start| start),
+            "Cannot disassemble a SegArray whose segments share an allocation"
+        );
+        let mut array = ManuallyDrop::new(self);
+        array.release_pool();
+        (
+            array.segments,
+            array.segment_usage,
+            array.allocated_segments,
+            array.count,
+        )
+    }
+
     pub fn len(&self) -> usize {
         self.count
     }
@@ -40,22 +207,69 @@ impl<T> SegArray<T> {
     }
 
     pub fn append(&mut self, value: T) {
-        let new_count = self.count + 1;
-        match self.grow(new_count) {
-            Ok(()) => {
-                let seg_idx = Self::segment_index(self.count);
-                let seg_slot = Self::segment_slot(self.count, seg_idx);
-                unsafe {
-                    let write_slot = self.segments[seg_idx].add(seg_slot);
-                    std::ptr::write(write_slot, value);
+        if let Err((_, e)) = self.try_append(value) {
+            Self::grow_failed(e)
+        }
+    }
+
+    /// Appends `value` and returns its index. If the array can't grow, `value` is handed back
+    /// along with the error instead of panicking.
+    pub fn try_append(&mut self, value: T) -> Result<usize, (T, SegArrayError)> {
+        if let Err(e) = self.try_reserve(1) {
+            return Err((value, e));
+        }
+        Ok(self.append_within_capacity(value))
+    }
+
+    /// Appends every item of `iter` after a single up-front reservation of `iter.len()` slots, so
+    /// no segment is allocated while those items are written. If the iterator yields more than
+    /// it reported, the extra items go through the normal growth path.
+    pub fn extend_exact<I: ExactSizeIterator<Item = T>>(&mut self, mut iter: I) {
+        let reserved_end = match self.count.checked_add(iter.len()) {
+            Some(end) => end,
+            None => Self::grow_failed(SegArrayError::CapacityOverflow),
+        };
+        if let Err(e) = self.grow(reserved_end) {
+            Self::grow_failed(e)
+        }
+
+        while self.count < reserved_end {
+            match iter.next() {
+                Some(value) => {
+                    self.append_within_capacity(value);
                 }
-                self.segment_usage[seg_idx] += 1;
-                self.count = new_count;
+                None => return,
             }
-            Err(e) => {
-                panic!("Failed to grow: {e:?}")
+        }
+        for value in iter {
+            self.append(value);
+        }
+    }
+
+    /// Builds an array from the first `len` items of `iter`, allocating exactly the segments
+    /// they need up front and filling each segment's slots in order. If the iterator runs out
+    /// early the array is just shorter; items past `len` are never pulled from it.
+    pub fn collect_with_len<I: IntoIterator<Item = T>>(iter: I, len: usize) -> Self {
+        let mut arr = Self::new();
+        if let Err(e) = arr.grow(len) {
+            Self::grow_failed(e)
+        }
+
+        let mut iter = iter.into_iter();
+        let mut guard = FillGuard::new(&mut arr);
+        'fill: for i in 0..guard.array.segment_count_for_capacity(len) {
+            let seg = guard.array.segments[i];
+            let fill = (len - guard.array.segment_start(i)).min(guard.array.segment_capacity(i));
+            for slot in 0..fill {
+                let Some(value) = iter.next() else {
+                    break 'fill;
+                };
+                unsafe { seg.add(slot).write(value) };
+                guard.len += 1;
             }
         }
+        drop(guard);
+        arr
     }
 
     pub fn pop(&mut self) -> Option<T> {
@@ -64,8 +278,8 @@ impl<T> SegArray<T> {
         }
 
         let idx = self.count - 1;
-        let seg_idx = Self::segment_index(idx);
-        let seg_slot = Self::segment_slot(idx, seg_idx);
+        let seg_idx = self.segment_index(idx);
+        let seg_slot = self.segment_slot(idx, seg_idx);
         let res = unsafe { self.segments[seg_idx].add(seg_slot).read() };
         self.segment_usage[seg_idx] -= 1;
         self.count = idx;
@@ -73,341 +287,3565 @@ impl<T> SegArray<T> {
         Some(res)
     }
 
-    // TODO: actual error types
-    fn grow(&mut self, new_count: usize) -> Result<(), ()> {
-        let new_segment_count = Self::segment_count_for_capacity(new_count);
-        let old_segment_count = self.allocated_segments;
-        if new_segment_count <= old_segment_count {
-            return Ok(());
+    /// Inserts `value` at `index`, shifting every element after it up by one. Panics if `index`
+    /// is greater than the length.
+    pub fn insert(&mut self, index: usize, value: T) {
+        if index > self.count {
+            panic!(
+                "Insertion index {index} is greater than length {}",
+                self.count
+            );
         }
 
-        for i in old_segment_count..new_segment_count {
-            debug_assert!(i < 32);
-            self.segments[i] = Self::alloc_seg(1 << i);
-            self.segment_usage[i] = 0;
+        self.reserve(1);
+        let len = self.count;
+        unsafe {
+            for i in (index..len).rev() {
+                std::ptr::copy_nonoverlapping(self.slot_ptr(i), self.slot_ptr(i + 1), 1);
+            }
+            self.slot_ptr(index).write(value);
         }
-        self.allocated_segments = new_segment_count;
-
-        Ok(())
+        self.segment_usage[self.segment_index(len)] += 1;
+        self.count = len + 1;
     }
 
-    fn alloc_seg(len: usize) -> *mut T {
-        let layout = Layout::array::<T>(len).expect("Layout error");
-        let ptr = unsafe { std::alloc::alloc(layout) as *mut T };
-        if ptr.is_null() {
-            std::alloc::handle_alloc_error(layout);
+    /// Inserts clones of `src` at `index`, shifting every element after it up by `src.len()`.
+    /// The tail moves one contiguous run at a time rather than once per inserted element.
+    /// Panics if `index` is greater than the length.
+    pub fn insert_slice(&mut self, index: usize, src: &[T])
+    where
+        T: Clone,
+    {
+        if index > self.count {
+            panic!(
+                "Insertion index {index} is greater than length {}",
+                self.count
+            );
+        }
+        if src.is_empty() {
+            return;
+        }
+
+        self.reserve(src.len());
+        let len = self.count;
+        let shift = src.len();
+        // Until the gap is filled the array only owns the elements before it, so a panicking
+        // clone leaks the tail rather than exposing uninitialized slots
+        self.set_count(index);
+        let mut end = len;
+        while end > index {
+            let from_slot = self.segment_slot(end - 1, self.segment_index(end - 1));
+            let to_slot = self.segment_slot(end - 1 + shift, self.segment_index(end - 1 + shift));
+            let run = (from_slot + 1).min(to_slot + 1).min(end - index);
+            unsafe {
+                std::ptr::copy(
+                    self.slot_ptr(end - run),
+                    self.slot_ptr(end - run + shift),
+                    run,
+                );
+            }
+            end -= run;
         }
-        ptr
+        for (i, item) in src.iter().enumerate() {
+            unsafe { self.slot_ptr(index + i).write(item.clone()) };
+        }
+        self.set_count(len + shift);
     }
 
-    fn segment_index(index: usize) -> usize {
-        (index + 1).ilog2().try_into().unwrap()
+    /// Inserts `value` into an array sorted in ascending order, keeping it sorted, and returns
+    /// the index it landed at. It goes after any elements equal to it.
+    pub fn insert_sorted(&mut self, value: T) -> usize
+    where
+        T: Ord,
+    {
+        let index = self.partition_point(|x| x <= &value);
+        self.insert(index, value);
+        index
     }
 
-    fn segment_slot(index: usize, segment_index: usize) -> usize {
-        index + 1 - (1 << (segment_index))
+    /// Merges two arrays that are each sorted by `cmp` into one sorted array, moving the
+    /// elements rather than cloning them. Equal elements from `self` come before those from
+    /// `other`.
+    pub fn merge_sorted(self, other: Self, cmp: impl Fn(&T, &T) -> Ordering) -> Self {
+        let mut out = self.empty_like();
+        out.reserve(self.count + other.count);
+        let mut left = self.into_iter().peekable();
+        let mut right = other.into_iter().peekable();
+        loop {
+            let take_left = match (left.peek(), right.peek()) {
+                (Some(a), Some(b)) => cmp(a, b) != Ordering::Greater,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            let next = if take_left { left.next() } else { right.next() };
+            out.append_within_capacity(next.unwrap());
+        }
+        out
     }
 
-    fn segment_count_for_capacity(capacity: usize) -> usize {
-        ilog2_ceil(capacity + 1)
+    /// Returns the index of the first element for which `pred` is false, assuming the array is
+    /// partitioned so that every element satisfying `pred` comes first.
+    pub fn partition_point<P: FnMut(&T) -> bool>(&self, mut pred: P) -> usize {
+        let mut low = 0;
+        let mut high = self.count;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(&self[mid]) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
     }
-}
 
-impl<T> Drop for SegArray<T> {
-    fn drop(&mut self) {
-        if self.allocated_segments == 0 {
-            return;
+    /// Pops the last element only if `pred` returns true for it.
+    pub fn pop_if<F: FnOnce(&T) -> bool>(&mut self, pred: F) -> Option<T> {
+        if pred(self.last()?) {
+            self.pop()
+        } else {
+            None
         }
+    }
 
-        // Before deallocating the buffers, we have to first drop each of the `T`s in the SegArray
-        let currently_filled_segments = Self::segment_count_for_capacity(self.count);
-        for i in 0..currently_filled_segments {
-            let seg = self.segments[i];
-            unsafe {
-                let filled_seg_as_slice =
-                    std::ptr::slice_from_raw_parts_mut(seg, self.segment_usage[i] - 1);
-                std::ptr::drop_in_place(filled_seg_as_slice);
+    /// Moves the contents out into a new array, leaving `self` empty with no segments allocated.
+    pub fn take(&mut self) -> SegArray<T> {
+        let empty = self.empty_like();
+        std::mem::replace(self, empty)
+    }
+
+    pub fn capacity(&self) -> usize {
+        (0..self.allocated_segments)
+            .map(|i| self.segment_capacity(i))
+            .sum()
+    }
+
+    /// Shortens the array to `len` elements, dropping the rest in reverse index order (the last
+    /// element is dropped first). Has no effect if `len` is not less than the current length.
+    pub fn truncate(&mut self, len: usize) {
+        // Elements without drop glue don't need visiting at all, which makes dropping or clearing
+        // a large array of plain data nearly free
+        if !std::mem::needs_drop::<T>() {
+            if len < self.count {
+                self.set_count(len);
             }
+            return;
         }
 
-        for i in 0..self.allocated_segments {
-            let seg = self.segments[i];
-            let layout = Layout::array::<T>(1 << i).unwrap();
-            unsafe {
-                std::alloc::dealloc(seg as *mut u8, layout);
-            }
+        while self.count > len {
+            let idx = self.count - 1;
+            let seg_idx = self.segment_index(idx);
+            // Update the bookkeeping first so a panicking `Drop` can't cause a double drop
+            self.segment_usage[seg_idx] -= 1;
+            self.count = idx;
+            unsafe { std::ptr::drop_in_place(self.slot_ptr(idx)) };
         }
     }
-}
-
-impl<T> Index<usize> for SegArray<T> {
-    type Output = T;
 
-    fn index(&self, index: usize) -> &Self::Output {
-        if index >= self.count {
-            panic!(
-                "Index out of bounds: index {index} is not less than length {}",
-                self.count
-            );
-        }
-        let seg_idx = Self::segment_index(index);
-        let seg_slot = Self::segment_slot(index, seg_idx);
-        unsafe { &*self.segments[seg_idx].add(seg_slot) }
+    /// Drops every element in reverse index order, keeping the allocated segments.
+    pub fn clear(&mut self) {
+        self.truncate(0);
     }
-}
 
-impl<T> IndexMut<usize> for SegArray<T> {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        if index >= self.count {
-            panic!(
-                "Index out of bounds: index {index} is not less than length {}",
-                self.count
-            );
-        }
-        let seg_idx = Self::segment_index(index);
-        let seg_slot = Self::segment_slot(index, seg_idx);
-        unsafe { &mut *self.segments[seg_idx].add(seg_slot) }
+    /// Deallocates every segment that holds no elements, including any kept by
+    /// [`SegArray::defragment`].
+    pub fn shrink_to_fit(&mut self) {
+        self.shrink_to(0);
     }
-}
 
-impl<T> IntoIterator for SegArray<T> {
-    type Item = T;
-    type IntoIter = SegArrayIntoIter<T>;
+    /// Deallocates segments that aren't needed to hold `max(len, min_capacity)` elements. The
+    /// capacity never drops below `min_capacity`, and nothing happens if it's already lower.
+    /// Segments allocated together by [`SegArray::reserve_contiguous`] are only freed together.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        let target = self.count.max(min_capacity).min(self.max_capacity());
+        let needed_segments = self.segment_count_for_capacity(target);
+        self.release_pool();
+        self.dealloc_segments_from(self.first_allocation_start_from(needed_segments));
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        let array = ManuallyDrop::new(self);
-        SegArrayIntoIter {
-            idx: 0,
-            count: array.count,
-            allocated_segments: array.allocated_segments,
-            segments: array.segments,
-            segment_usage: array.segment_usage,
-            _marker: PhantomData
+    /// Moves the segments that hold no elements into an internal pool rather than freeing them,
+    /// so that growing again reuses those buffers instead of allocating new ones. Like
+    /// [`SegArray::shrink_to`], segments sharing an allocation are only moved together.
+    pub fn defragment(&mut self) {
+        let needed_segments = self.segment_count_for_capacity(self.count);
+        let first_unneeded = self.first_allocation_start_from(needed_segments);
+        for i in first_unneeded..self.allocated_segments {
+            self.free_segments[i] = std::mem::replace(&mut self.segments[i], std::ptr::null_mut());
+            self.segment_usage[i] = 0;
         }
+        self.allocated_segments = first_unneeded;
     }
-}
-
-pub struct SegArrayIntoIter<T> {
-    idx: usize,
-    count: usize,
-    allocated_segments: usize,
-    segments: [*mut T; 32],
-    segment_usage: [usize; 32],
-    _marker: PhantomData<T>,
-}
 
-impl<T> Iterator for SegArrayIntoIter<T> {
-    type Item = T;
+    pub fn truncate_and_shrink(&mut self, len: usize) {
+        self.truncate(len);
+        self.shrink_to_fit();
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.idx == self.count {
-            None
-        } else {
-            let seg_idx = SegArray::<T>::segment_index(self.idx);
-            let seg_slot = SegArray::<T>::segment_slot(self.idx, seg_idx);
-            let item = unsafe { self.segments[seg_idx].add(seg_slot).read() };
-            self.idx += 1;
-            Some(item)
+    /// Moves every element of `other` onto the end of `self`, leaving `other` empty. `other`
+    /// keeps its allocated segments.
+    pub fn append_all(&mut self, other: &mut Self) {
+        self.reserve(other.count);
+        for i in 0..other.count {
+            let value = unsafe { other.slot_ptr(i).read() };
+            self.append(value);
         }
+        other.set_count(0);
     }
-}
 
-impl<T> Drop for SegArrayIntoIter<T> {
-    fn drop(&mut self) {
-        // Need to drop all elements in indices [idx, count). The ones before idx have already been
-        // moved out, so dropping them is wrong.
-        let first_seg_including_drop = SegArray::<T>::segment_count_for_capacity(self.idx + 1) - 1;
-        let currently_filled_segments = SegArray::<T>::segment_count_for_capacity(self.count);
-        let mut already_dropped = self.idx;
-        for i in first_seg_including_drop..currently_filled_segments {
-            let drop_slice_start_slot = SegArray::<T>::segment_slot(already_dropped, i);
-            let drop_slice = unsafe { self.segments[i].add(drop_slice_start_slot) };
-            let drop_slice_len = ((1 << i) - drop_slice_start_slot).min(self.segment_usage[i]);
-            unsafe {
-                let filled_seg_as_slice =
-                    std::ptr::slice_from_raw_parts_mut(drop_slice, drop_slice_len);
-                std::ptr::drop_in_place(filled_seg_as_slice);
-            }
-            already_dropped += drop_slice_len;
+    pub fn extend_from_slice(&mut self, other: &[T])
+    where
+        T: Clone,
+    {
+        self.reserve(other.len());
+        let mut guard = FillGuard::new(self);
+        for item in other {
+            unsafe { guard.write_next(item.clone()) };
         }
+    }
 
-        for i in 0..self.allocated_segments {
-            let layout = Layout::array::<T>(1 << i).unwrap();
-            unsafe {
-                std::alloc::dealloc(self.segments[i] as *mut u8, layout);
+    /// Returns a new array holding the current elements repeated `n` times.
+    pub fn repeat(&self, n: usize) -> SegArray<T>
+    where
+        T: Clone,
+    {
+        let total = self
+            .count
+            .checked_mul(n)
+            .unwrap_or_else(|| Self::grow_failed(SegArrayError::CapacityOverflow));
+        let mut out = self.empty_like();
+        if total == 0 {
+            return out;
+        }
+        out.reserve(total);
+        for _ in 0..n {
+            for run in self.segments() {
+                out.extend_from_slice(run);
             }
         }
+        out
     }
-}
 
-fn ilog2_ceil(x: usize) -> usize {
-    assert!(x != 0);
-    let l2 = x.ilog2();
-    if 1 << l2 == x {
-        l2.try_into().unwrap()
-    } else {
-        (l2 + 1).try_into().unwrap()
+    /// Appends clones of the elements in `range` to the end of the array.
+    pub fn extend_from_within<R: RangeBounds<usize>>(&mut self, range: R)
+    where
+        T: Clone,
+    {
+        let range = self.resolve_range(range);
+        self.reserve(range.len());
+        for i in range {
+            let value = self[i].clone();
+            self.append(value);
+        }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn simple() {
-        let mut arr: SegArray<i32> = SegArray::new();
-        arr.append(1);
-        assert_eq!(arr[0], 1);
+    /// Returns an iterator that removes and yields every element matching `pred`, shifting the
+    /// remaining elements down to close the gaps. Elements are visited in order and the
+    /// compaction finishes when the iterator is dropped, even if it wasn't fully consumed.
+    pub fn extract_if<F: FnMut(&mut T) -> bool>(&mut self, pred: F) -> ExtractIf<'_, T, F> {
+        let old_len = self.count;
+        // Until the iterator is dropped the array is in flux, so if it gets leaked we'd rather
+        // leak the elements than expose moved-out slots
+        self.set_count(0);
+        ExtractIf {
+            array: self,
+            pred,
+            idx: 0,
+            del: 0,
+            old_len,
+        }
     }
 
-    #[test]
-    fn basic_usage() {
-        let mut arr: SegArray<i32> = SegArray::new();
-
-        for i in 0..100 {
-            arr.append(i);
-            assert_eq!(arr.len(), (i + 1).try_into().unwrap());
+    pub fn reserve(&mut self, additional: usize) {
+        if let Err(e) = self.try_reserve(additional) {
+            Self::grow_failed(e)
         }
+    }
 
-        for i in 0..100 {
-            assert_eq!(arr[i], i.try_into().unwrap());
+    /// Like [`SegArray::reserve`], but all of the newly needed segments are carved out of one
+    /// allocation instead of one allocation each. Since segment `i` starts at element
+    /// `2^i - 1`, the new segments sit back to back in that buffer in element order.
+    pub fn reserve_contiguous(&mut self, additional: usize) {
+        let result = self
+            .count
+            .checked_add(additional)
+            .ok_or(SegArrayError::CapacityOverflow)
+            .and_then(|new_count| self.grow_contiguous(new_count));
+        if let Err(e) = result {
+            Self::grow_failed(e)
         }
+    }
 
-        assert_eq!(arr.pop(), Some(99));
-        assert_eq!(arr.len(), 99);
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), SegArrayError> {
+        let new_count = self
+            .count
+            .checked_add(additional)
+            .ok_or(SegArrayError::CapacityOverflow)?;
+        self.grow(new_count)
+    }
 
-        for (x, item) in arr.into_iter().take(21).enumerate() {
-            println!("{x}");
-            assert_eq!(item, x.try_into().unwrap());
+    /// Like [`SegArray::reserve`], but leaves the highest segment the new elements would need
+    /// unallocated until an element actually lands in it. Reserving a few slots past a segment
+    /// boundary then doesn't allocate the whole next segment, which for the later segments is as
+    /// large as everything before it combined. Afterwards the capacity may still be short of
+    /// `len() + additional`, so this isn't enough before writing into spare capacity.
+    pub fn reserve_lazy(&mut self, additional: usize) {
+        let new_count = self
+            .count
+            .checked_add(additional)
+            .filter(|&new_count| new_count <= self.max_capacity())
+            .unwrap_or_else(|| Self::grow_failed(SegArrayError::CapacityOverflow));
+        if let Some(top) = self.segment_count_for_capacity(new_count).checked_sub(1) {
+            if let Err(e) = self.grow(self.segment_start(top)) {
+                Self::grow_failed(e)
+            }
         }
     }
 
-    #[test]
-    fn test_empty_array() {
-        let mut arr: SegArray<u8> = SegArray::new();
-        assert!(arr.is_empty());
-        assert_eq!(arr.len(), 0);
-        assert_eq!(arr.pop(), None);
-        assert_eq!(arr.into_iter().next(), None);
+    /// Returns the base pointer of segment `i`, or null if that segment is not allocated.
+    ///
+    /// Elements never move once written, so the pointer stays valid until the segment is
+    /// deallocated, which only happens in [`SegArray::shrink_to`] (and the methods built on it)
+    /// or when the `SegArray` is dropped. Only the first `segment_len(i)` slots are initialized.
+    ///
+    /// What `segment_ptr(i)` returns can change without the old buffer being freed.
+    /// [`SegArray::defragment`] moves empty segments into its pool, so they read as null until
+    /// growth hands the same buffers back. [`SegArray::take`] moves every segment into the
+    /// returned array, where the old pointers remain valid.
+    pub fn segment_ptr(&self, i: usize) -> *const T {
+        self.segment_ptr_raw(i)
     }
 
-    #[test]
-    fn test_segment_growth_and_indexing() {
-        let mut arr: SegArray<i32> = SegArray::new();
-        // The capacity of segments are 1, 2, 4, 8...
-        // Total capacities are 1, 3, 7, 15, 31...
-        let num_elements = 35;
-        for i in 0..num_elements {
-            arr.append(i);
-            // Verify all previous elements are still correct after each append
-            for j in 0..=i {
-                assert_eq!(
-                    arr[j.try_into().unwrap()],
-                    j,
-                    "Mismatch at index {} after appending {}",
-                    j,
-                    i
-                );
-            }
-        }
-        assert_eq!(arr.len(), num_elements as usize);
-        assert_eq!(arr.allocated_segments, 6); // 1+2+4+8+16+32 > 35, so 6 segments
+    /// Mutable counterpart of [`SegArray::segment_ptr`], with the same validity guarantees.
+    pub fn segment_ptr_mut(&mut self, i: usize) -> *mut T {
+        self.segment_ptr_raw(i)
     }
 
-    #[test]
-    fn test_pop_to_empty() {
-        let mut arr: SegArray<i32> = SegArray::new();
-        for i in 0..50 {
-            arr.append(i);
+    /// The number of slots segment `i` holds once allocated, or 0 past the last segment.
+    pub fn segment_capacity(&self, i: usize) -> usize {
+        if i >= MAX_SEGMENTS {
+            0
+        } else if self.is_capped() {
+            layout::capped_segment_len(i, self.max_segment_shift)
+        } else {
+            1 << i
         }
+    }
 
-        for i in (0..50).rev() {
-            assert_eq!(arr.len(), (i + 1) as usize);
-            assert_eq!(arr.pop(), Some(i));
+    pub fn segment_len(&self, i: usize) -> usize {
+        if i < self.allocated_segments {
+            self.segment_usage[i]
+        } else {
+            0
         }
+    }
 
-        assert_eq!(arr.len(), 0);
-        assert!(arr.is_empty());
-        assert_eq!(arr.pop(), None);
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index < self.count {
+            Some(unsafe { &*self.slot_ptr(index) })
+        } else {
+            None
+        }
     }
 
-    #[test]
-    fn test_index_mut() {
-        let mut arr: SegArray<i32> = SegArray::new();
-        for i in 0..20 {
-            arr.append(i);
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index < self.count {
+            Some(unsafe { &mut *self.slot_ptr(index) })
+        } else {
+            None
         }
+    }
 
-        // Modify value at the start
-        arr[0] = 100;
-        assert_eq!(arr[0], 100);
+    /// Like [`SegArray::get`], but negative indices count back from the end, so `-1` is the last
+    /// element.
+    pub fn get_rel(&self, index: isize) -> Option<&T> {
+        let index = if index < 0 {
+            self.count.checked_sub(index.unsigned_abs())?
+        } else {
+            index as usize
+        };
+        self.get(index)
+    }
 
-        // Modify value in a later segment (index 10 is in segment 3)
-        arr[10] = 200;
-        assert_eq!(arr[10], 200);
+    pub fn try_get(&self, index: usize) -> Result<&T, SegArrayError> {
+        let len = self.count;
+        self.get(index)
+            .ok_or(SegArrayError::IndexOutOfBounds { index, len })
+    }
 
-        // Modify value at the end
-        arr[19] = 300;
-        assert_eq!(arr[19], 300);
+    pub fn try_get_mut(&mut self, index: usize) -> Result<&mut T, SegArrayError> {
+        let len = self.count;
+        self.get_mut(index)
+            .ok_or(SegArrayError::IndexOutOfBounds { index, len })
     }
 
-    #[test]
-    fn test_iterator_on_multiple_segments() {
-        let mut arr: SegArray<i32> = SegArray::new();
-        let count = 25;
-        for i in 0..count {
-            arr.append(i);
+    /// Returns the element at `index`, first appending `default()` values until the array is long
+    /// enough to have one.
+    pub fn get_mut_or_append(&mut self, index: usize, default: impl Fn() -> T) -> &mut T {
+        if index >= self.count {
+            let new_count = index
+                .checked_add(1)
+                .unwrap_or_else(|| Self::grow_failed(SegArrayError::CapacityOverflow));
+            let additional = new_count - self.count;
+            self.reserve(additional);
+            for _ in 0..additional {
+                self.append(default());
+            }
         }
+        &mut self[index]
+    }
 
-        let collected: Vec<i32> = arr.into_iter().collect();
-        let expected: Vec<i32> = (0..count).collect();
+    pub fn first(&self) -> Option<&T> {
+        self.get(0)
+    }
 
-        assert_eq!(collected, expected);
+    pub fn last(&self) -> Option<&T> {
+        self.count.checked_sub(1).and_then(|idx| self.get(idx))
     }
 
-    #[test]
+    /// Returns references to the first `N` elements, or `None` if there are fewer than `N`.
+    pub fn first_chunk<const N: usize>(&self) -> Option<[&T; N]> {
+        if self.count < N {
+            return None;
+        }
+        Some(std::array::from_fn(|i| &self[i]))
+    }
+
+    /// Returns references to the last `N` elements, or `None` if there are fewer than `N`.
+    pub fn last_chunk<const N: usize>(&self) -> Option<[&T; N]> {
+        let start = self.count.checked_sub(N)?;
+        Some(std::array::from_fn(|i| &self[start + i]))
+    }
+
+    /// Returns true if the array begins with `needle`. An empty `needle` always matches.
+    pub fn starts_with(&self, needle: &[T]) -> bool
+    where
+        T: PartialEq,
+    {
+        needle.len() <= self.count && needle.iter().enumerate().all(|(i, x)| self[i] == *x)
+    }
+
+    /// Returns true if the array ends with `needle`. An empty `needle` always matches.
+    pub fn ends_with(&self, needle: &[T]) -> bool
+    where
+        T: PartialEq,
+    {
+        let Some(start) = self.count.checked_sub(needle.len()) else {
+            return false;
+        };
+        needle
+            .iter()
+            .enumerate()
+            .all(|(i, x)| self[start + i] == *x)
+    }
+
+    /// Returns a view over the whole array.
+    pub fn as_view(&self) -> SegSliceView<'_, T> {
+        SegSliceView {
+            array: self,
+            start: 0,
+            end: self.count,
+        }
+    }
+
+    /// Returns a view over the elements in `range`.
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> SegSliceView<'_, T> {
+        let range = self.resolve_range(range);
+        SegSliceView {
+            array: self,
+            start: range.start,
+            end: range.end,
+        }
+    }
+
+    pub fn split_first(&self) -> Option<(&T, SegSliceView<'_, T>)> {
+        let first = self.first()?;
+        Some((first, self.slice(1..)))
+    }
+
+    pub fn split_last(&self) -> Option<(&T, SegSliceView<'_, T>)> {
+        let last = self.last()?;
+        Some((last, self.slice(..self.count - 1)))
+    }
+
+    /// Recovers the index of an element from a reference to it, or `None` if `elem` doesn't
+    /// point into this array. This relies on elements never moving once they're appended.
+    pub fn index_of(&self, elem: &T) -> Option<usize> {
+        let size = std::mem::size_of::<T>();
+        // Every zero-sized element shares an address, so there's no way to tell them apart
+        if size == 0 {
+            return None;
+        }
+
+        let addr = elem as *const T as usize;
+        let populated_segments = self.segment_count_for_capacity(self.count);
+        for i in 0..populated_segments {
+            let base = self.segments[i] as usize;
+            let end = base + self.segment_usage[i] * size;
+            if (base..end).contains(&addr) {
+                let offset = addr - base;
+                if !offset.is_multiple_of(size) {
+                    return None;
+                }
+                return Some(self.segment_start(i) + offset / size);
+            }
+        }
+        None
+    }
+
+    /// Iterates over the live elements of each populated segment as contiguous slices.
+    pub fn segments(&self) -> impl Iterator<Item = &[T]> + '_ {
+        (0..self.segment_count_for_capacity(self.count)).map(move |i| self.segment_slice(i))
+    }
+
+    /// Like [`segments`](Self::segments), but pairs each slice with its segment index. Segment
+    /// `i` holds up to `2^i` elements.
+    pub fn enumerate_segments(&self) -> impl Iterator<Item = (usize, &[T])> + '_ {
+        self.segments().enumerate()
+    }
+
+    /// Returns the live elements of each populated segment as mutable slices. Segments never
+    /// overlap, so the slices are disjoint and can be handed to different threads.
+    pub fn segment_slices_mut(&mut self) -> Vec<&mut [T]> {
+        (0..self.segment_count_for_capacity(self.count))
+            .map(|i| unsafe {
+                std::slice::from_raw_parts_mut(self.segments[i], self.segment_usage[i])
+            })
+            .collect()
+    }
+
+    /// Returns the unused slots of every allocated segment, in element order, as uninitialized
+    /// slices. Filling them from the front and then calling [`SegArray::set_len`] commits the
+    /// written elements without copying them.
+    pub fn spare_capacity_segments_mut(&mut self) -> Vec<&mut [MaybeUninit<T>]> {
+        let first_spare = self.segment_index(self.count);
+        (first_spare..self.allocated_segments)
+            .map(|i| {
+                let used = self.segment_usage[i];
+                unsafe {
+                    std::slice::from_raw_parts_mut(
+                        self.segments[i].add(used) as *mut MaybeUninit<T>,
+                        self.segment_capacity(i) - used,
+                    )
+                }
+            })
+            .collect()
+    }
+
+    /// Sets the length of the array, along with how full each segment is.
+    ///
+    /// # Safety
+    ///
+    /// `new_len` must be at most [`SegArray::capacity`], and when growing, every element between
+    /// the old and new length must already be initialized (for example through
+    /// [`SegArray::spare_capacity_segments_mut`]). When shrinking, the elements past `new_len`
+    /// are not dropped.
+    pub unsafe fn set_len(&mut self, new_len: usize) {
+        debug_assert!(new_len <= self.capacity());
+        self.set_count(new_len);
+    }
+
+    /// Copies every element into a new `Vec`, one contiguous segment run at a time.
+    pub fn copy_to_vec(&self) -> Vec<T>
+    where
+        T: Copy,
+    {
+        let mut out = Vec::with_capacity(self.count);
+        for run in self.segments() {
+            out.extend_from_slice(run);
+        }
+        out
+    }
+
+    /// Clears `scratch` and fills it with clones of every element, one segment run at a time,
+    /// returning its contents. Reusing the same `scratch` across calls avoids reallocating.
+    pub fn to_contiguous<'a>(&self, scratch: &'a mut Vec<T>) -> &'a [T]
+    where
+        T: Clone,
+    {
+        scratch.clear();
+        scratch.reserve(self.count);
+        for run in self.segments() {
+            scratch.extend_from_slice(run);
+        }
+        scratch
+    }
+
+    /// Overwrites every element with the contents of `src`, copying one segment run at a time.
+    /// Panics if `src` isn't exactly as long as the array.
+    pub fn copy_from_slice(&mut self, src: &[T])
+    where
+        T: Copy,
+    {
+        if src.len() != self.count {
+            panic!(
+                "Source slice length {} does not match array length {}",
+                src.len(),
+                self.count
+            );
+        }
+
+        let mut offset = 0;
+        for i in 0..self.segment_count_for_capacity(self.count) {
+            let run_len = self.segment_usage[i];
+            unsafe {
+                std::ptr::copy_nonoverlapping(src.as_ptr().add(offset), self.segments[i], run_len);
+            }
+            offset += run_len;
+        }
+    }
+
+    /// Overwrites the elements in `range` with clones of `src`, dropping the old values, one
+    /// segment run at a time. Nothing is shifted. Panics if `src` isn't exactly as long as the
+    /// range.
+    pub fn replace_block<R: RangeBounds<usize>>(&mut self, range: R, src: &[T])
+    where
+        T: Clone,
+    {
+        let range = self.resolve_range(range);
+        if src.len() != range.len() {
+            panic!(
+                "Source slice length {} does not match range length {}",
+                src.len(),
+                range.len()
+            );
+        }
+
+        let mut index = range.start;
+        let mut src = src;
+        while !src.is_empty() {
+            let seg_idx = self.segment_index(index);
+            let seg_slot = self.segment_slot(index, seg_idx);
+            let run_len = (self.segment_usage[seg_idx] - seg_slot).min(src.len());
+            let run = unsafe {
+                std::slice::from_raw_parts_mut(self.segments[seg_idx].add(seg_slot), run_len)
+            };
+            let (head, rest) = src.split_at(run_len);
+            run.clone_from_slice(head);
+            src = rest;
+            index += run_len;
+        }
+    }
+
+    /// Collects a reference to every element, in order.
+    pub fn as_vec_refs(&self) -> Vec<&T> {
+        let mut refs = Vec::with_capacity(self.count);
+        for run in self.segments() {
+            refs.extend(run.iter());
+        }
+        refs
+    }
+
+    /// Calls `f` on every element in order, walking each segment as a slice so the inner loop
+    /// runs over contiguous memory.
+    pub fn for_each<F: FnMut(&T)>(&self, mut f: F) {
+        for run in self.segments() {
+            run.iter().for_each(&mut f);
+        }
+    }
+
+    /// Folds every element into an accumulator in order, one contiguous segment at a time.
+    pub fn fold<B, F: FnMut(B, &T) -> B>(&self, init: B, mut f: F) -> B {
+        let mut acc = init;
+        for run in self.segments() {
+            acc = run.iter().fold(acc, &mut f);
+        }
+        acc
+    }
+
+    /// Folds over the array one populated segment at a time, handing `f` each segment's live
+    /// elements as a slice.
+    pub fn bytes_fold<B, F: FnMut(B, &[T]) -> B>(&self, init: B, f: F) -> B {
+        self.segments().fold(init, f)
+    }
+
+    pub fn iter(&self) -> SegArrayIter<'_, T> {
+        SegArrayIter {
+            array: self,
+            front: 0,
+            back: self.count,
+        }
+    }
+
+    /// Returns the range of valid indices. It doesn't borrow the array, so elements can be
+    /// modified through those indices while iterating.
+    pub fn indices(&self) -> Range<usize> {
+        0..self.count
+    }
+
+    /// Iterates over `(index, &element)` pairs, taking each index straight from the iterator's
+    /// cursor.
+    pub fn iter_enumerated(&self) -> impl Iterator<Item = (usize, &T)> + '_ {
+        let mut iter = self.iter();
+        std::iter::from_fn(move || {
+            let index = iter.front;
+            iter.next().map(|item| (index, item))
+        })
+    }
+
+    /// Panics if the internal segment bookkeeping is inconsistent. Only available with debug
+    /// assertions, as a way for tests to catch corruption right after the operation causing it.
+    #[cfg(debug_assertions)]
+    pub fn assert_invariants(&self) {
+        let populated_segments = self.segment_count_for_capacity(self.count);
+        assert!(
+            self.allocated_segments >= populated_segments,
+            "{} elements need {populated_segments} segments but only {} are allocated",
+            self.count,
+            self.allocated_segments
+        );
+        assert!(self.allocated_segments <= MAX_SEGMENTS);
+
+        let usage_total: usize = self.segment_usage[..populated_segments].iter().sum();
+        assert_eq!(
+            usage_total, self.count,
+            "Segment usage doesn't add up to the length"
+        );
+        for i in 0..populated_segments.saturating_sub(1) {
+            assert_eq!(
+                self.segment_usage[i],
+                self.segment_capacity(i),
+                "Segment {i} isn't full but a later segment is in use"
+            );
+        }
+
+        for i in 0..MAX_SEGMENTS {
+            if i < self.allocated_segments {
+                assert!(
+                    !self.segments[i].is_null(),
+                    "Segment {i} is allocated but null"
+                );
+                assert!(
+                    self.free_segments[i].is_null(),
+                    "Segment {i} is allocated but also pooled"
+                );
+            } else {
+                assert!(
+                    self.segments[i].is_null(),
+                    "Segment {i} is unallocated but non-null"
+                );
+                if self.free_segments[i].is_null() {
+                    assert!(!self.allocation_starts[i]);
+                    assert!(
+                        self.free_segments[i..].iter().all(|seg| seg.is_null()),
+                        "Pooled segments must sit directly above the allocated ones"
+                    );
+                }
+            }
+            if i >= populated_segments {
+                assert_eq!(
+                    self.segment_usage[i], 0,
+                    "Unpopulated segment {i} has usage"
+                );
+            }
+        }
+        if self.allocated_segments > 0 {
+            assert!(
+                self.allocation_starts[0],
+                "Segment 0 must start an allocation"
+            );
+        }
+    }
+
+    /// Iterates over pairs of elements at the same index in `self` and `other`, stopping at the
+    /// end of the shorter array.
+    pub fn zip<'a, U>(
+        &'a self,
+        other: &'a SegArray<U>,
+    ) -> impl Iterator<Item = (&'a T, &'a U)> + 'a {
+        let len = self.count.min(other.count);
+        (0..len).map(move |i| unsafe { (&*self.slot_ptr(i), &*other.slot_ptr(i)) })
+    }
+
+    // Converts any range over logical indices into a `start..end` range, panicking if it isn't
+    // within the array
+    fn resolve_range<R: RangeBounds<usize>>(&self, range: R) -> Range<usize> {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start.checked_add(1).expect("Range start overflows usize"),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end.checked_add(1).expect("Range end overflows usize"),
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.count,
+        };
+        if start > end {
+            panic!("Range start {start} is greater than range end {end}");
+        }
+        if end > self.count {
+            panic!("Range end {end} is out of bounds for length {}", self.count);
+        }
+        start..end
+    }
+
+    // Writes `value` into the next slot and returns its index. The slot's segment must already be
+    // allocated.
+    fn append_within_capacity(&mut self, value: T) -> usize {
+        let idx = self.count;
+        let seg_idx = self.segment_index(idx);
+        let seg_slot = self.segment_slot(idx, seg_idx);
+        debug_assert!(seg_idx < self.allocated_segments);
+        unsafe {
+            let write_slot = self.segments[seg_idx].add(seg_slot);
+            std::ptr::write(write_slot, value);
+        }
+        self.segment_usage[seg_idx] += 1;
+        self.count = idx + 1;
+        idx
+    }
+
+    // Sets the length and recomputes the per-segment usage to match. Nothing is dropped or
+    // initialized, so the caller is responsible for the slots in between.
+    fn set_count(&mut self, count: usize) {
+        for i in 0..self.allocated_segments {
+            let segment_start = self.segment_start(i);
+            self.segment_usage[i] = count
+                .saturating_sub(segment_start)
+                .min(self.segment_capacity(i));
+        }
+        self.count = count;
+    }
+
+    // Resolves a logical index to its slot without bounds checking; `index` must be below the
+    // allocated capacity.
+    fn slot_ptr(&self, index: usize) -> *mut T {
+        let seg_idx = self.segment_index(index);
+        let seg_slot = self.segment_slot(index, seg_idx);
+        // A corrupted length or segment table would otherwise turn into a wild dereference
+        debug_assert!(
+            seg_idx < self.allocated_segments && !self.segments[seg_idx].is_null(),
+            "Index {index} resolves to segment {seg_idx}, which isn't allocated"
+        );
+        debug_assert!(
+            seg_slot < self.segment_capacity(seg_idx),
+            "Index {index} resolves to slot {seg_slot}, past the end of segment {seg_idx}"
+        );
+        unsafe { self.segments[seg_idx].add(seg_slot) }
+    }
+
+    fn segment_slice(&self, i: usize) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.segments[i], self.segment_usage[i]) }
+    }
+
+    fn segment_ptr_raw(&self, i: usize) -> *mut T {
+        if i < self.allocated_segments {
+            self.segments[i]
+        } else {
+            std::ptr::null_mut()
+        }
+    }
+
+    fn grow(&mut self, new_count: usize) -> Result<(), SegArrayError> {
+        // Anything larger would need a segment past the end of the segment table
+        if new_count > self.max_capacity() {
+            return Err(SegArrayError::CapacityOverflow);
+        }
+
+        let new_segment_count = self.segment_count_for_capacity(new_count);
+        if new_segment_count <= self.allocated_segments {
+            return Ok(());
+        }
+
+        self.reuse_pooled(new_segment_count);
+        for i in self.allocated_segments..new_segment_count {
+            self.segments[i] = Self::alloc_seg(self.segment_capacity(i))?;
+            self.segment_usage[i] = 0;
+            self.allocation_starts[i] = true;
+            // Record each segment as it's allocated so that a later failure doesn't leak it
+            self.allocated_segments = i + 1;
+        }
+
+        Ok(())
+    }
+
+    fn grow_contiguous(&mut self, new_count: usize) -> Result<(), SegArrayError> {
+        if new_count > self.max_capacity() {
+            return Err(SegArrayError::CapacityOverflow);
+        }
+
+        let new_segment_count = self.segment_count_for_capacity(new_count);
+        self.reuse_pooled(new_segment_count);
+        let old_segment_count = self.allocated_segments;
+        if new_segment_count <= old_segment_count {
+            return Ok(());
+        }
+
+        let base = Self::alloc_seg(self.segments_len(old_segment_count, new_segment_count))?;
+        for i in old_segment_count..new_segment_count {
+            let offset = self.segments_len(old_segment_count, i);
+            self.segments[i] = unsafe { base.add(offset) };
+            self.segment_usage[i] = 0;
+            self.allocation_starts[i] = i == old_segment_count;
+        }
+        self.allocated_segments = new_segment_count;
+
+        Ok(())
+    }
+
+    // Moves whole pooled allocations back into use until `segment_count` segments are allocated
+    // or the pool runs out. This can leave more than `segment_count` segments allocated.
+    fn reuse_pooled(&mut self, segment_count: usize) {
+        while self.allocated_segments < segment_count
+            && !self.free_segments[self.allocated_segments].is_null()
+        {
+            let start = self.allocated_segments;
+            let end = self.pooled_allocation_end(start);
+            for i in start..end {
+                self.segments[i] =
+                    std::mem::replace(&mut self.free_segments[i], std::ptr::null_mut());
+                self.segment_usage[i] = 0;
+            }
+            self.allocated_segments = end;
+        }
+    }
+
+    // Deallocates everything in the pool
+    fn release_pool(&mut self) {
+        let mut start = self.allocated_segments;
+        while start < MAX_SEGMENTS && !self.free_segments[start].is_null() {
+            let end = self.pooled_allocation_end(start);
+            Self::dealloc_seg(self.free_segments[start], self.segments_len(start, end));
+            for i in start..end {
+                self.free_segments[i] = std::ptr::null_mut();
+                self.allocation_starts[i] = false;
+            }
+            start = end;
+        }
+    }
+
+    fn pooled_allocation_end(&self, start: usize) -> usize {
+        (start + 1..MAX_SEGMENTS)
+            .find(|&i| self.allocation_starts[i] || self.free_segments[i].is_null())
+            .unwrap_or(MAX_SEGMENTS)
+    }
+
+    // The first allocated segment at or after `segment` that starts its own allocation
+    fn first_allocation_start_from(&self, segment: usize) -> usize {
+        (segment..self.allocated_segments)
+            .find(|&i| self.allocation_starts[i])
+            .unwrap_or(self.allocated_segments)
+    }
+
+    // Deallocates every segment from `first` onwards. `first` has to be the start of an
+    // allocation (or `allocated_segments`) so that no buffer is only partially released.
+    fn dealloc_segments_from(&mut self, first: usize) {
+        let mut start = first;
+        while start < self.allocated_segments {
+            debug_assert!(self.allocation_starts[start]);
+            let end = (start + 1..self.allocated_segments)
+                .find(|&i| self.allocation_starts[i])
+                .unwrap_or(self.allocated_segments);
+            Self::dealloc_seg(self.segments[start], self.segments_len(start, end));
+            for i in start..end {
+                self.segments[i] = std::ptr::null_mut();
+                self.segment_usage[i] = 0;
+                self.allocation_starts[i] = false;
+            }
+            start = end;
+        }
+        self.allocated_segments = self.allocated_segments.min(first);
+    }
+
+    fn segments_len(&self, start: usize, end: usize) -> usize {
+        layout::segments_len(start, end, self.max_segment_shift)
+    }
+
+    // The combined size of all `MAX_SEGMENTS` segments
+    fn max_capacity(&self) -> usize {
+        if self.is_capped() {
+            layout::capped_segment_start(MAX_SEGMENTS, self.max_segment_shift)
+        } else {
+            MAX_CAPACITY
+        }
+    }
+
+    // Every segment lookup checks this first so that default arrays go straight to the plain
+    // doubling math
+    fn is_capped(&self) -> bool {
+        self.max_segment_shift != layout::UNCAPPED
+    }
+
+    // An empty array with the same segment sizes as this one
+    fn empty_like(&self) -> Self {
+        let mut arr = Self::new();
+        arr.max_segment_shift = self.max_segment_shift;
+        arr
+    }
+
+    fn grow_failed(e: SegArrayError) -> ! {
+        match e {
+            SegArrayError::AllocError { layout } => std::alloc::handle_alloc_error(layout),
+            e => panic!("Failed to grow: {e:?}"),
+        }
+    }
+
+    fn alloc_seg(len: usize) -> Result<*mut T, SegArrayError> {
+        let layout = Layout::array::<T>(len).map_err(|_| SegArrayError::CapacityOverflow)?;
+        let ptr = unsafe { std::alloc::alloc(layout) as *mut T };
+        if ptr.is_null() {
+            return Err(SegArrayError::AllocError { layout });
+        }
+        Ok(ptr)
+    }
+
+    fn dealloc_seg(seg: *mut T, len: usize) {
+        let layout = Layout::array::<T>(len).unwrap();
+        unsafe {
+            std::alloc::dealloc(seg as *mut u8, layout);
+        }
+    }
+
+    fn segment_index(&self, index: usize) -> usize {
+        if self.is_capped() {
+            layout::capped_segment_index(index, self.max_segment_shift)
+        } else {
+            layout::segment_index(index)
+        }
+    }
+
+    fn segment_slot(&self, index: usize, segment_index: usize) -> usize {
+        if self.is_capped() {
+            layout::capped_segment_slot(index, segment_index, self.max_segment_shift)
+        } else {
+            layout::segment_slot(index, segment_index)
+        }
+    }
+
+    fn segment_start(&self, segment_index: usize) -> usize {
+        if self.is_capped() {
+            layout::capped_segment_start(segment_index, self.max_segment_shift)
+        } else {
+            layout::segment_start(segment_index)
+        }
+    }
+
+    fn segment_count_for_capacity(&self, capacity: usize) -> usize {
+        if self.is_capped() {
+            layout::capped_segment_count_for_capacity(capacity, self.max_segment_shift)
+        } else {
+            layout::segment_count_for_capacity(capacity)
+        }
+    }
+}
+
+/// A [`SegArray`] of slots that can be emptied without shifting anything after them, so an
+/// index stays valid until its own value is removed. Emptied slots are refilled, lowest first,
+/// before the array grows again.
+#[derive(Debug, Clone)]
+pub struct SegArena<T> {
+    slots: SegArray<Option<T>>,
+    // Every `None` slot, lowest first. Slots are only emptied and refilled through the arena, so
+    // this never goes stale.
+    vacated: BinaryHeap<Reverse<usize>>,
+}
+
+impl<T> Default for SegArena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SegArena<T> {
+    pub const fn new() -> Self {
+        Self {
+            slots: SegArray::new(),
+            vacated: BinaryHeap::new(),
+        }
+    }
+
+    /// The number of slots, including vacated ones.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Returns the value in slot `index`, or `None` if it's out of bounds or vacated.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.slots.get(index)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.slots.get_mut(index)?.as_mut()
+    }
+
+    /// The underlying slots, with `None` for each vacated one.
+    pub fn slots(&self) -> &SegArray<Option<T>> {
+        &self.slots
+    }
+
+    /// Takes the value out of slot `index`, leaving a `None` tombstone so no other index changes.
+    /// Returns `None` if the slot is out of bounds or already vacated.
+    pub fn remove_tombstone(&mut self, index: usize) -> Option<T> {
+        let value = self.slots.get_mut(index)?.take()?;
+        self.vacated.push(Reverse(index));
+        Some(value)
+    }
+
+    /// Stores `value` in the lowest slot vacated by [`SegArena::remove_tombstone`], or appends it
+    /// if there are none, and returns its index. Vacated slots are tracked as they're removed, so
+    /// this doesn't scan the array.
+    pub fn append_reuse(&mut self, value: T) -> usize {
+        if let Some(Reverse(index)) = self.vacated.pop() {
+            let slot = &mut self.slots[index];
+            debug_assert!(slot.is_none(), "Vacated slot {index} is occupied");
+            *slot = Some(value);
+            return index;
+        }
+        self.slots.append(Some(value));
+        self.slots.len() - 1
+    }
+}
+
+impl<T: Clone> Clone for SegArray<T> {
+    fn clone(&self) -> Self {
+        let mut out = self.empty_like();
+        if let Err(e) = out.grow(self.count) {
+            Self::grow_failed(e)
+        }
+
+        let mut guard = FillGuard::new(&mut out);
+        for (i, run) in self.segments().enumerate() {
+            let seg = guard.array.segments[i];
+            for (slot, item) in run.iter().enumerate() {
+                unsafe { seg.add(slot).write(item.clone()) };
+                guard.len += 1;
+            }
+        }
+        drop(guard);
+        out
+    }
+}
+
+// Tracks elements written into the allocated slots past the end of `array` and sets its length
+// to cover them when dropped. If producing the next element panics, the ones already written
+// end up owned by the array and are dropped exactly once along with it.
+struct FillGuard<'a, T> {
+    array: &'a mut SegArray<T>,
+    len: usize,
+}
+
+impl<'a, T> FillGuard<'a, T> {
+    fn new(array: &'a mut SegArray<T>) -> Self {
+        let len = array.count;
+        Self { array, len }
+    }
+
+    // The slot after the last written element must already be allocated
+    unsafe fn write_next(&mut self, value: T) {
+        let seg_idx = self.array.segment_index(self.len);
+        let seg_slot = self.array.segment_slot(self.len, seg_idx);
+        debug_assert!(seg_idx < self.array.allocated_segments);
+        unsafe { self.array.segments[seg_idx].add(seg_slot).write(value) };
+        self.len += 1;
+    }
+}
+
+impl<T> Drop for FillGuard<'_, T> {
+    fn drop(&mut self) {
+        self.array.set_count(self.len);
+    }
+}
+
+impl<T> Drop for SegArray<T> {
+    fn drop(&mut self) {
+        // If a `T::drop` panics, the guard goes on dropping the elements `clear` hadn't reached
+        // yet and still frees the buffers. A second panic while unwinding aborts, as with `Vec`.
+        struct DropGuard<'a, T>(&'a mut SegArray<T>);
+
+        impl<T> Drop for DropGuard<'_, T> {
+            fn drop(&mut self) {
+                self.0.clear();
+                self.0.release_pool();
+                self.0.dealloc_segments_from(0);
+            }
+        }
+
+        // Before deallocating the buffers, we have to first drop each of the `T`s in the SegArray
+        let guard = DropGuard(self);
+        guard.0.clear();
+    }
+}
+
+impl<T> Index<usize> for SegArray<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        if index >= self.count {
+            panic!(
+                "Index out of bounds: index {index} is not less than length {}",
+                self.count
+            );
+        }
+        unsafe { &*self.slot_ptr(index) }
+    }
+}
+
+impl<T> IndexMut<usize> for SegArray<T> {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        if index >= self.count {
+            panic!(
+                "Index out of bounds: index {index} is not less than length {}",
+                self.count
+            );
+        }
+        unsafe { &mut *self.slot_ptr(index) }
+    }
+}
+
+impl<T, const N: usize> From<[T; N]> for SegArray<T> {
+    fn from(value: [T; N]) -> Self {
+        let mut arr = Self::new();
+        arr.reserve(N);
+        for item in value {
+            arr.append(item);
+        }
+        arr
+    }
+}
+
+impl<T: Clone> From<&[T]> for SegArray<T> {
+    fn from(value: &[T]) -> Self {
+        let mut arr = Self::new();
+        arr.extend_from_slice(value);
+        arr
+    }
+}
+
+impl<T> From<SegArray<T>> for Box<[T]> {
+    fn from(mut array: SegArray<T>) -> Self {
+        let len = array.count;
+        let mut vec = Vec::with_capacity(len);
+        // Moving the elements is a bitwise copy of each segment, so nothing can panic between
+        // the copies and `array` giving up ownership of them
+        let mut dst = vec.as_mut_ptr();
+        for i in 0..array.segment_count_for_capacity(len) {
+            let used = array.segment_usage[i];
+            unsafe {
+                std::ptr::copy_nonoverlapping(array.segments[i], dst, used);
+                dst = dst.add(used);
+            }
+        }
+        array.set_count(0);
+        unsafe { vec.set_len(len) };
+        vec.into_boxed_slice()
+    }
+}
+
+impl<T: PartialEq, const N: usize> PartialEq<[T; N]> for SegArray<T> {
+    fn eq(&self, other: &[T; N]) -> bool {
+        self.count == N && self.iter().zip(other).all(|(a, b)| a == b)
+    }
+}
+
+impl<T: PartialEq, const N: usize> PartialEq<SegArray<T>> for [T; N] {
+    fn eq(&self, other: &SegArray<T>) -> bool {
+        other == self
+    }
+}
+
+impl io::Write for SegArray<u8> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Each rayon job fills its own array, and the arrays are joined in order at the end
+#[cfg(feature = "rayon")]
+impl<T: Send> rayon::iter::FromParallelIterator<T> for SegArray<T> {
+    fn from_par_iter<I>(par_iter: I) -> Self
+    where
+        I: rayon::iter::IntoParallelIterator<Item = T>,
+    {
+        use rayon::iter::ParallelIterator;
+        use std::collections::LinkedList;
+
+        let parts: LinkedList<SegArray<T>> = par_iter
+            .into_par_iter()
+            .fold(SegArray::new, |mut arr, item| {
+                arr.append(item);
+                arr
+            })
+            .map(|arr| LinkedList::from([arr]))
+            .reduce(LinkedList::new, |mut front, mut back| {
+                front.append(&mut back);
+                front
+            });
+
+        let mut out = SegArray::new();
+        out.reserve(parts.iter().map(SegArray::len).sum());
+        for mut part in parts {
+            out.append_all(&mut part);
+        }
+        out
+    }
+}
+
+impl<T> IntoIterator for SegArray<T> {
+    type Item = T;
+    type IntoIter = SegArrayIntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        SegArrayIntoIter {
+            idx: 0,
+            array: self,
+        }
+    }
+}
+
+pub struct SegArrayIntoIter<T> {
+    idx: usize,
+    array: SegArray<T>,
+}
+
+impl<T> SegArrayIntoIter<T> {
+    /// Returns references to the elements that haven't been yielded yet, in order.
+    pub fn as_slice(&self) -> Vec<&T> {
+        (self.idx..self.array.count)
+            .map(|i| &self.array[i])
+            .collect()
+    }
+}
+
+impl<T> Iterator for SegArrayIntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx == self.array.count {
+            None
+        } else {
+            let item = unsafe { self.array.slot_ptr(self.idx).read() };
+            self.idx += 1;
+            Some(item)
+        }
+    }
+}
+
+impl<T> Drop for SegArrayIntoIter<T> {
+    fn drop(&mut self) {
+        // Need to drop all elements in indices [idx, count). The ones before idx have already been
+        // moved out, so dropping them is wrong. Emptying the array first means its own `Drop`
+        // only deallocates the segments, and a panicking element leaks the rest instead of
+        // dropping anything twice.
+        let count = self.array.count;
+        self.array.set_count(0);
+        if !std::mem::needs_drop::<T>() {
+            return;
+        }
+        for i in self.idx..count {
+            unsafe { std::ptr::drop_in_place(self.array.slot_ptr(i)) };
+        }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a SegArray<T> {
+    type Item = &'a T;
+    type IntoIter = SegArrayIter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct SegArrayIter<'a, T> {
+    array: &'a SegArray<T>,
+    // Logical indices of the next element from the front and one past the next from the back
+    front: usize,
+    back: usize,
+}
+
+impl<'a, T> Iterator for SegArrayIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            None
+        } else {
+            let item = unsafe { &*self.array.slot_ptr(self.front) };
+            self.front += 1;
+            Some(item)
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.back - self.front;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<T> DoubleEndedIterator for SegArrayIter<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            None
+        } else {
+            self.back -= 1;
+            Some(unsafe { &*self.array.slot_ptr(self.back) })
+        }
+    }
+}
+
+impl<T> ExactSizeIterator for SegArrayIter<'_, T> {}
+
+pub struct ExtractIf<'a, T, F>
+where
+    F: FnMut(&mut T) -> bool,
+{
+    array: &'a mut SegArray<T>,
+    pred: F,
+    // The next index to visit, and how many elements have been removed before it
+    idx: usize,
+    del: usize,
+    old_len: usize,
+}
+
+impl<T, F> Iterator for ExtractIf<'_, T, F>
+where
+    F: FnMut(&mut T) -> bool,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.idx < self.old_len {
+            let slot = self.array.slot_ptr(self.idx);
+            let extract = (self.pred)(unsafe { &mut *slot });
+            // Only advance once the predicate has returned, so a panic in it leaves this element
+            // to be shifted down by `drop`
+            self.idx += 1;
+            if extract {
+                self.del += 1;
+                return Some(unsafe { slot.read() });
+            } else if self.del > 0 {
+                let dst = self.array.slot_ptr(self.idx - 1 - self.del);
+                unsafe { std::ptr::copy_nonoverlapping(slot, dst, 1) };
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.old_len - self.idx))
+    }
+}
+
+impl<T, F> Drop for ExtractIf<'_, T, F>
+where
+    F: FnMut(&mut T) -> bool,
+{
+    fn drop(&mut self) {
+        // Shift the unvisited tail down over the gaps left by extracted elements
+        if self.del > 0 {
+            for i in self.idx..self.old_len {
+                let src = self.array.slot_ptr(i);
+                let dst = self.array.slot_ptr(i - self.del);
+                unsafe { std::ptr::copy_nonoverlapping(src, dst, 1) };
+            }
+        }
+        self.array.set_count(self.old_len - self.del);
+    }
+}
+
+/// A borrowed view over a contiguous range of logical indices in a [`SegArray`].
+pub struct SegSliceView<'a, T> {
+    array: &'a SegArray<T>,
+    start: usize,
+    end: usize,
+}
+
+impl<'a, T> SegSliceView<'a, T> {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'a T> {
+        if index < self.len() {
+            self.array.get(self.start + index)
+        } else {
+            None
+        }
+    }
+
+    pub fn first(&self) -> Option<&'a T> {
+        self.get(0)
+    }
+
+    pub fn last(&self) -> Option<&'a T> {
+        self.len().checked_sub(1).and_then(|idx| self.get(idx))
+    }
+
+    pub fn iter(&self) -> SegArrayIter<'a, T> {
+        SegArrayIter {
+            array: self.array,
+            front: self.start,
+            back: self.end,
+        }
+    }
+
+    pub fn split_first(&self) -> Option<(&'a T, SegSliceView<'a, T>)> {
+        let first = self.first()?;
+        Some((
+            first,
+            SegSliceView {
+                array: self.array,
+                start: self.start + 1,
+                end: self.end,
+            },
+        ))
+    }
+
+    pub fn split_last(&self) -> Option<(&'a T, SegSliceView<'a, T>)> {
+        let last = self.last()?;
+        Some((
+            last,
+            SegSliceView {
+                array: self.array,
+                start: self.start,
+                end: self.end - 1,
+            },
+        ))
+    }
+}
+
+impl<'a, T> From<&'a SegArray<T>> for SegSliceView<'a, T> {
+    fn from(array: &'a SegArray<T>) -> Self {
+        array.as_view()
+    }
+}
+
+impl<T> Clone for SegSliceView<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SegSliceView<'_, T> {}
+
+impl<T: fmt::Debug> fmt::Debug for SegSliceView<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T> Index<usize> for SegSliceView<'_, T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        match self.get(index) {
+            Some(item) => item,
+            None => panic!(
+                "Index out of bounds: index {index} is not less than length {}",
+                self.len()
+            ),
+        }
+    }
+}
+
+impl<'a, T> IntoIterator for SegSliceView<'a, T> {
+    type Item = &'a T;
+    type IntoIter = SegArrayIter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn simple() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        arr.append(1);
+        assert_eq!(arr[0], 1);
+    }
+
+    #[test]
+    fn basic_usage() {
+        let mut arr: SegArray<i32> = SegArray::new();
+
+        for i in 0..100 {
+            arr.append(i);
+            assert_eq!(arr.len(), (i + 1).try_into().unwrap());
+        }
+
+        for i in 0..100 {
+            assert_eq!(arr[i], i.try_into().unwrap());
+        }
+
+        assert_eq!(arr.pop(), Some(99));
+        assert_eq!(arr.len(), 99);
+
+        for (x, item) in arr.into_iter().take(21).enumerate() {
+            println!("{x}");
+            assert_eq!(item, x.try_into().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_empty_array() {
+        let mut arr: SegArray<u8> = SegArray::new();
+        assert!(arr.is_empty());
+        assert_eq!(arr.len(), 0);
+        assert_eq!(arr.pop(), None);
+        assert_eq!(arr.into_iter().next(), None);
+    }
+
+    #[test]
+    fn test_segment_growth_and_indexing() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        // The capacity of segments are 1, 2, 4, 8...
+        // Total capacities are 1, 3, 7, 15, 31...
+        let num_elements = 35;
+        for i in 0..num_elements {
+            arr.append(i);
+            // Verify all previous elements are still correct after each append
+            for j in 0..=i {
+                assert_eq!(
+                    arr[j.try_into().unwrap()],
+                    j,
+                    "Mismatch at index {} after appending {}",
+                    j,
+                    i
+                );
+            }
+        }
+        assert_eq!(arr.len(), num_elements as usize);
+        assert_eq!(arr.allocated_segments, 6); // 1+2+4+8+16+32 > 35, so 6 segments
+    }
+
+    #[test]
+    fn test_pop_to_empty() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        for i in 0..50 {
+            arr.append(i);
+        }
+
+        for i in (0..50).rev() {
+            assert_eq!(arr.len(), (i + 1) as usize);
+            assert_eq!(arr.pop(), Some(i));
+        }
+
+        assert_eq!(arr.len(), 0);
+        assert!(arr.is_empty());
+        assert_eq!(arr.pop(), None);
+    }
+
+    #[test]
+    fn test_index_mut() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        for i in 0..20 {
+            arr.append(i);
+        }
+
+        // Modify value at the start
+        arr[0] = 100;
+        assert_eq!(arr[0], 100);
+
+        // Modify value in a later segment (index 10 is in segment 3)
+        arr[10] = 200;
+        assert_eq!(arr[10], 200);
+
+        // Modify value at the end
+        arr[19] = 300;
+        assert_eq!(arr[19], 300);
+    }
+
+    #[test]
+    fn test_iterator_on_multiple_segments() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        let count = 25;
+        for i in 0..count {
+            arr.append(i);
+        }
+
+        let collected: Vec<i32> = arr.into_iter().collect();
+        let expected: Vec<i32> = (0..count).collect();
+
+        assert_eq!(collected, expected);
+    }
+
+    #[test]
     fn test_with_string_type() {
         let mut arr: SegArray<String> = SegArray::new();
-        arr.append("hello".to_string());
-        arr.append("world".to_string());
+        arr.append("hello".to_string());
+        arr.append("world".to_string());
+
+        assert_eq!(arr.len(), 2);
+        assert_eq!(arr[0], "hello".to_string());
+        assert_eq!(arr[1], "world".to_string());
+
+        assert_eq!(arr.pop(), Some("world".to_string()));
+        assert_eq!(arr.pop(), Some("hello".to_string()));
+        assert_eq!(arr.pop(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Index out of bounds: index 2 is not less than length 2")]
+    fn test_out_of_bounds_panic() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        arr.append(10);
+        arr.append(20);
+
+        // This should panic
+        let _ = arr[2];
+    }
+
+    #[test]
+    fn test_internal_indexing_helpers() {
+        // segment_index(index) -> (index + 1).ilog2()
+        assert_eq!(layout::segment_index(0), 0); // 1.ilog2() -> 0
+        assert_eq!(layout::segment_index(1), 1); // 2.ilog2() -> 1
+        assert_eq!(layout::segment_index(2), 1); // 3.ilog2() -> 1
+        assert_eq!(layout::segment_index(3), 2); // 4.ilog2() -> 2
+        assert_eq!(layout::segment_index(6), 2); // 7.ilog2() -> 2
+        assert_eq!(layout::segment_index(7), 3); // 8.ilog2() -> 3
+
+        // segment_slot(index, seg_idx) -> index + 1 - (1 << seg_idx)
+        assert_eq!(layout::segment_slot(0, 0), 0); // 0+1 - 2^0 = 0
+        assert_eq!(layout::segment_slot(1, 1), 0); // 1+1 - 2^1 = 0
+        assert_eq!(layout::segment_slot(2, 1), 1); // 2+1 - 2^1 = 1
+        assert_eq!(layout::segment_slot(3, 2), 0); // 3+1 - 2^2 = 0
+        assert_eq!(layout::segment_slot(6, 2), 3); // 6+1 - 2^2 = 3
+        assert_eq!(layout::segment_slot(7, 3), 0); // 7+1 - 2^3 = 0
+    }
+
+    #[test]
+    fn test_from_array() {
+        let arr = SegArray::from([1, 2, 3, 4, 5]);
+        assert_eq!(arr.len(), 5);
+        assert_eq!(arr.allocated_segments, 3);
+        for i in 0..5 {
+            assert_eq!(arr[i], i as i32 + 1);
+        }
+
+        let empty: SegArray<i32> = SegArray::from([]);
+        assert!(empty.is_empty());
+        assert_eq!(empty.allocated_segments, 0);
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let source = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let arr = SegArray::from(source.as_slice());
+        assert_eq!(arr.len(), 3);
+        assert_eq!(arr[0], "a");
+        assert_eq!(arr[2], "c");
+        assert_eq!(source.len(), 3);
+    }
+
+    #[test]
+    fn test_segment_raw_accessors() {
+        let mut arr: SegArray<u32> = SegArray::new();
+        assert!(arr.segment_ptr(0).is_null());
+        assert_eq!(arr.segment_len(0), 0);
+
+        for i in 0..10 {
+            arr.append(i);
+        }
+
+        // Segments hold 1, 2, 4 and 3 (of 8) elements respectively
+        let mut expected = 0;
+        for i in 0..4 {
+            assert_eq!(arr.segment_capacity(i), 1 << i);
+            let len = arr.segment_len(i);
+            let run = unsafe { std::slice::from_raw_parts(arr.segment_ptr(i), len) };
+            for &item in run {
+                assert_eq!(item, expected);
+                expected += 1;
+            }
+        }
+        assert_eq!(expected, 10);
+        assert_eq!(arr.segment_len(3), 3);
+        assert!(arr.segment_ptr(4).is_null());
+        assert!(arr.segment_ptr(40).is_null());
+
+        unsafe { *arr.segment_ptr_mut(2).add(1) = 100 };
+        assert_eq!(arr[4], 100);
+    }
+
+    #[test]
+    fn test_iter_forward_and_reverse() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        for i in 0..40 {
+            arr.append(i);
+        }
+
+        let forward: Vec<i32> = arr.iter().copied().collect();
+        assert_eq!(forward, (0..40).collect::<Vec<_>>());
+
+        let reversed: Vec<i32> = arr.iter().rev().copied().collect();
+        assert_eq!(reversed, (0..40).rev().collect::<Vec<_>>());
+
+        let mut iter = arr.iter();
+        assert_eq!(iter.len(), 40);
+        assert_eq!(iter.next(), Some(&0));
+        assert_eq!(iter.next_back(), Some(&39));
+        assert_eq!(iter.len(), 38);
+    }
+
+    #[test]
+    fn test_iter_cursors_meet() {
+        let arr = SegArray::from([0, 1, 2, 3, 4, 5, 6]);
+        let mut iter = arr.iter();
+        let mut seen = Vec::new();
+        // Alternate ends so the cursors cross the segment boundaries from both sides
+        while let Some(&x) = iter.next() {
+            seen.push(x);
+            match iter.next_back() {
+                Some(&x) => seen.push(x),
+                None => break,
+            }
+        }
+        assert_eq!(seen, vec![0, 6, 1, 5, 2, 4, 3]);
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+
+        let empty: SegArray<i32> = SegArray::new();
+        assert_eq!(empty.iter().next_back(), None);
+    }
+
+    #[derive(Clone)]
+    struct DropLogger {
+        id: usize,
+        log: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Drop for DropLogger {
+        fn drop(&mut self) {
+            self.log.borrow_mut().push(self.id);
+        }
+    }
+
+    fn logged_array(len: usize, log: &Rc<RefCell<Vec<usize>>>) -> SegArray<DropLogger> {
+        let mut arr = SegArray::new();
+        for id in 0..len {
+            arr.append(DropLogger {
+                id,
+                log: Rc::clone(log),
+            });
+        }
+        arr
+    }
+
+    #[test]
+    fn test_truncate_and_clear_drop_in_reverse_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut arr = logged_array(12, &log);
+
+        arr.truncate(20);
+        assert_eq!(arr.len(), 12);
+        assert!(log.borrow().is_empty());
+
+        arr.truncate(5);
+        assert_eq!(arr.len(), 5);
+        assert_eq!(*log.borrow(), vec![11, 10, 9, 8, 7, 6, 5]);
+
+        log.borrow_mut().clear();
+        arr.clear();
+        assert!(arr.is_empty());
+        assert_eq!(*log.borrow(), vec![4, 3, 2, 1, 0]);
+        assert_eq!(arr.allocated_segments, 4);
+    }
+
+    #[test]
+    fn test_drop_drops_every_element() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let arr = logged_array(15, &log);
+        drop(arr);
+        assert_eq!(*log.borrow(), (0..15).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        for i in 0..20 {
+            arr.append(i);
+        }
+        assert_eq!(arr.capacity(), 31);
+
+        arr.truncate(6);
+        arr.shrink_to_fit();
+        assert_eq!(arr.allocated_segments, 3);
+        assert_eq!(arr.capacity(), 7);
+        assert!(arr.segment_ptr(3).is_null());
+        assert_eq!(
+            arr.iter().copied().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4, 5]
+        );
+
+        arr.truncate_and_shrink(0);
+        assert_eq!(arr.capacity(), 0);
+        arr.append(7);
+        assert_eq!(arr[0], 7);
+    }
+
+    #[test]
+    fn test_append_all() {
+        let mut arr = SegArray::from(["a".to_string(), "b".to_string()]);
+        let mut other = SegArray::new();
+        for i in 0..10 {
+            other.append(i.to_string());
+        }
+
+        arr.append_all(&mut other);
+        assert_eq!(arr.len(), 12);
+        assert_eq!(arr[1], "b");
+        assert_eq!(arr[2], "0");
+        assert_eq!(arr[11], "9");
+        assert!(other.is_empty());
+
+        other.append("x".to_string());
+        assert_eq!(other[0], "x");
+
+        let mut empty = SegArray::new();
+        arr.append_all(&mut empty);
+        assert_eq!(arr.len(), 12);
+    }
+
+    #[test]
+    fn test_try_append_and_try_reserve() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        assert_eq!(arr.try_append(5), Ok(0));
+        assert_eq!(arr.try_append(6), Ok(1));
+        assert_eq!(arr[1], 6);
+
+        assert_eq!(arr.try_reserve(10), Ok(()));
+        assert_eq!(arr.capacity(), 15);
+        assert_eq!(
+            arr.try_reserve(usize::MAX),
+            Err(SegArrayError::CapacityOverflow)
+        );
+        assert_eq!(arr.len(), 2);
+        assert_eq!(arr.capacity(), 15);
+    }
+
+    #[test]
+    fn test_index_of() {
+        let mut arr: SegArray<u64> = SegArray::new();
+        for i in 0..50 {
+            arr.append(i);
+        }
+
+        for i in 0..50 {
+            assert_eq!(arr.index_of(&arr[i]), Some(i));
+        }
+
+        let outside = 7;
+        assert_eq!(arr.index_of(&outside), None);
+
+        let other = SegArray::from([1u64, 2, 3]);
+        assert_eq!(arr.index_of(&other[1]), None);
+
+        // Popped slots no longer count as part of the array
+        let last = &arr[49] as *const u64;
+        arr.pop();
+        assert_eq!(arr.index_of(unsafe { &*last }), None);
+    }
+
+    #[test]
+    fn test_extend_from_within() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        for i in 0..10 {
+            arr.append(i);
+        }
+
+        // Source lives in segments 1 and 2, the copies land in segment 3
+        arr.extend_from_within(2..5);
+        assert_eq!(arr.len(), 13);
+        assert_eq!(
+            arr.iter().copied().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 2, 3, 4]
+        );
+
+        arr.extend_from_within(11..);
+        assert_eq!(arr.len(), 15);
+        assert_eq!(arr[13], 3);
+        assert_eq!(arr[14], 4);
+
+        arr.extend_from_within(..0);
+        assert_eq!(arr.len(), 15);
+
+        let mut strings = SegArray::from(["x".to_string(), "y".to_string()]);
+        strings.extend_from_within(..=1);
+        assert_eq!(strings[3], "y");
+    }
+
+    #[test]
+    #[should_panic(expected = "Range end 4 is out of bounds for length 3")]
+    fn test_extend_from_within_out_of_bounds() {
+        let mut arr = SegArray::from([1, 2, 3]);
+        arr.extend_from_within(1..4);
+    }
+
+    #[test]
+    fn test_get_first_last() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        assert_eq!(arr.get(0), None);
+        assert_eq!(arr.first(), None);
+        assert_eq!(arr.last(), None);
+
+        for i in 0..9 {
+            arr.append(i);
+        }
+        assert_eq!(arr.get(8), Some(&8));
+        assert_eq!(arr.get(9), None);
+        assert_eq!(arr.first(), Some(&0));
+        assert_eq!(arr.last(), Some(&8));
+
+        *arr.get_mut(3).unwrap() = 30;
+        assert_eq!(arr[3], 30);
+        assert!(arr.get_mut(9).is_none());
+    }
+
+    #[test]
+    fn test_split_first_and_last() {
+        let arr = SegArray::from([1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let (first, rest) = arr.split_first().unwrap();
+        assert_eq!(*first, 1);
+        assert_eq!(rest.len(), 7);
+        assert_eq!(rest[0], 2);
+        assert_eq!(
+            rest.iter().copied().collect::<Vec<_>>(),
+            vec![2, 3, 4, 5, 6, 7, 8]
+        );
+
+        let (last, rest) = arr.split_last().unwrap();
+        assert_eq!(*last, 8);
+        assert_eq!(rest.last(), Some(&7));
+
+        // Peel elements off both ends of a view until it's empty
+        let mut view = arr.slice(..);
+        let mut peeled = Vec::new();
+        while let Some((head, tail)) = view.split_first() {
+            peeled.push(*head);
+            view = tail;
+            if let Some((end, init)) = view.split_last() {
+                peeled.push(*end);
+                view = init;
+            }
+        }
+        assert_eq!(peeled, vec![1, 8, 2, 7, 3, 6, 4, 5]);
+        assert!(view.is_empty());
+
+        let single = SegArray::from([9]);
+        let (only, rest) = single.split_last().unwrap();
+        assert_eq!(*only, 9);
+        assert!(rest.is_empty());
+
+        let empty: SegArray<i32> = SegArray::new();
+        assert!(empty.split_first().is_none());
+        assert!(empty.split_last().is_none());
+    }
+
+    #[test]
+    fn test_segments_and_copy_to_vec() {
+        let mut arr: SegArray<u8> = SegArray::new();
+        assert_eq!(arr.segments().count(), 0);
+        assert!(arr.copy_to_vec().is_empty());
+
+        for i in 0..12 {
+            arr.append(i);
+        }
+        let runs: Vec<&[u8]> = arr.segments().collect();
+        assert_eq!(
+            runs,
+            vec![&[0][..], &[1, 2], &[3, 4, 5, 6], &[7, 8, 9, 10, 11]]
+        );
+        assert_eq!(arr.copy_to_vec(), (0..12).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_const_new() {
+        static EMPTY: SegArray<u8> = SegArray::new();
+        const ALSO_EMPTY: SegArray<u8> = SegArray::EMPTY;
+
+        assert!(EMPTY.is_empty());
+        assert_eq!(EMPTY.capacity(), 0);
+
+        let mut arr = ALSO_EMPTY;
+        arr.append(1);
+        assert_eq!(arr[0], 1);
+    }
+
+    #[test]
+    fn test_extend_from_slice() {
+        let mut arr = SegArray::from([1, 2]);
+        arr.extend_from_slice(&[3, 4, 5, 6, 7]);
+        arr.extend_from_slice(&[]);
+        assert_eq!(arr.copy_to_vec(), vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_io_write() {
+        use std::io::Write;
+
+        let mut arr: SegArray<u8> = SegArray::new();
+        assert_eq!(arr.write(b"hello").unwrap(), 5);
+        let name = "world";
+        write!(arr, ", {name}!").unwrap();
+        arr.flush().unwrap();
+        assert_eq!(arr.copy_to_vec(), b"hello, world!");
+    }
+
+    #[test]
+    fn test_for_each_and_fold() {
+        let mut arr: SegArray<u64> = SegArray::new();
+        for i in 0..1000 {
+            arr.append(i);
+        }
+
+        let mut visited = Vec::new();
+        arr.for_each(|&x| visited.push(x));
+        assert_eq!(visited, (0..1000).collect::<Vec<_>>());
+
+        assert_eq!(arr.fold(0, |acc, &x| acc + x), 999 * 1000 / 2);
+        assert_eq!(SegArray::<u64>::new().fold(7, |acc, &x| acc + x), 7);
+    }
+
+    #[test]
+    fn test_extract_if() {
+        let mut arr: SegArray<String> = SegArray::new();
+        for i in 0..20 {
+            arr.append(i.to_string());
+        }
+
+        // Matches and non-matches alternate across every segment boundary
+        let evens: Vec<String> = arr
+            .extract_if(|s| s.parse::<i32>().unwrap() % 2 == 0)
+            .collect();
+        assert_eq!(
+            evens,
+            (0..20)
+                .step_by(2)
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(arr.len(), 10);
+        assert_eq!(
+            arr.iter().cloned().collect::<Vec<_>>(),
+            (1..20)
+                .step_by(2)
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+        );
+
+        arr.append("tail".to_string());
+        assert_eq!(arr[10], "tail");
+    }
+
+    #[test]
+    fn test_extract_if_partially_consumed() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        for i in 0..15 {
+            arr.append(i);
+        }
+
+        let mut iter = arr.extract_if(|x| *x % 3 == 0);
+        assert_eq!(iter.next(), Some(0));
+        assert_eq!(iter.next(), Some(3));
+        drop(iter);
+
+        // Only the elements visited before the drop are removed
+        assert_eq!(
+            arr.copy_to_vec(),
+            vec![1, 2, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]
+        );
+
+        let removed: Vec<i32> = arr.extract_if(|_| false).collect();
+        assert!(removed.is_empty());
+        assert_eq!(arr.len(), 13);
+
+        let removed: Vec<i32> = arr.extract_if(|_| true).collect();
+        assert_eq!(removed.len(), 13);
+        assert!(arr.is_empty());
+    }
+
+    #[test]
+    fn test_capacity_overflow() {
+        assert_eq!(
+            layout::segment_count_for_capacity(MAX_CAPACITY),
+            MAX_SEGMENTS
+        );
+
+        let mut arr: SegArray<u8> = SegArray::new();
+        assert_eq!(
+            arr.try_reserve(MAX_CAPACITY + 1),
+            Err(SegArrayError::CapacityOverflow)
+        );
+        assert_eq!(arr.allocated_segments, 0);
+
+        // Pretend the array is already full rather than actually allocating 4GiB
+        arr.count = MAX_CAPACITY;
+        assert_eq!(arr.try_append(1), Err((1, SegArrayError::CapacityOverflow)));
+        assert_eq!(arr.len(), MAX_CAPACITY);
+        arr.count = 0;
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to grow: CapacityOverflow")]
+    fn test_reserve_past_max_capacity_panics() {
+        let mut arr: SegArray<u8> = SegArray::new();
+        arr.reserve(MAX_CAPACITY + 1);
+    }
+
+    #[test]
+    fn test_shrink_to() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        for i in 0..100 {
+            arr.append(i);
+        }
+        assert_eq!(arr.capacity(), 127);
+        arr.truncate(3);
+
+        arr.shrink_to(20);
+        assert_eq!(arr.capacity(), 31);
+
+        // Already below the requested floor, so nothing changes
+        arr.shrink_to(1000);
+        assert_eq!(arr.capacity(), 31);
+
+        // The live elements still set the floor
+        arr.shrink_to(0);
+        assert_eq!(arr.capacity(), 3);
+        assert_eq!(arr.copy_to_vec(), vec![0, 1, 2]);
+
+        arr.shrink_to(usize::MAX);
+        assert_eq!(arr.capacity(), 3);
+    }
+
+    #[test]
+    fn test_into_iter_partial_drop() {
+        // Takes of nothing, one, up to and across segment boundaries, and almost everything
+        let cases = [
+            (0, 0),
+            (1, 0),
+            (1, 1),
+            (2, 1),
+            (4, 3),
+            (4, 4),
+            (5, 4),
+            (7, 3),
+            (8, 7),
+            (15, 7),
+            (15, 8),
+            (99, 0),
+            (99, 1),
+            (99, 21),
+            (99, 63),
+            (99, 64),
+            (99, 98),
+            (99, 99),
+        ];
+        for (len, take) in cases {
+            let log = Rc::new(RefCell::new(Vec::new()));
+            let consumed: Vec<DropLogger> =
+                logged_array(len, &log).into_iter().take(take).collect();
+            assert_eq!(consumed.len(), take);
+
+            let mut dropped = log.borrow().clone();
+            dropped.sort_unstable();
+            assert_eq!(
+                dropped,
+                (take..len).collect::<Vec<_>>(),
+                "len {len}, take {take}: remaining elements must be dropped exactly once"
+            );
+
+            drop(consumed);
+            let mut dropped = log.borrow().clone();
+            dropped.sort_unstable();
+            assert_eq!(dropped, (0..len).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_with_segments() {
+        let arr: SegArray<u16> = SegArray::with_segments(0);
+        assert_eq!(arr.allocated_segments, 0);
+
+        let mut arr: SegArray<u16> = SegArray::with_segments(5);
+        assert_eq!(arr.allocated_segments, 5);
+        assert_eq!(arr.capacity(), 31);
+        assert!(arr.is_empty());
+        for i in 0..5 {
+            assert!(!arr.segment_ptr(i).is_null());
+        }
+
+        for i in 0..31 {
+            arr.append(i);
+        }
+        assert_eq!(arr.allocated_segments, 5);
+        arr.append(31);
+        assert_eq!(arr.allocated_segments, 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot allocate 33 segments, the maximum is 32")]
+    fn test_with_too_many_segments() {
+        let _ = SegArray::<u8>::with_segments(33);
+    }
+
+    #[test]
+    fn test_reserve_contiguous() {
+        let mut arr: SegArray<u32> = SegArray::new();
+        arr.reserve_contiguous(100);
+        assert_eq!(arr.allocated_segments, 7);
+        assert_eq!(arr.capacity(), 127);
+        // All segments share one buffer, laid out in element order
+        for i in 0..7 {
+            assert_eq!(arr.segment_ptr(i), unsafe {
+                arr.segment_ptr(0).add((1 << i) - 1)
+            });
+        }
+
+        for i in 0..100 {
+            arr.append(i);
+        }
+        assert_eq!(arr.allocated_segments, 7);
+
+        // The shared buffer can't be partially freed
+        arr.truncate(10);
+        arr.shrink_to_fit();
+        assert_eq!(arr.allocated_segments, 7);
+        assert_eq!(arr.copy_to_vec(), (0..10).collect::<Vec<_>>());
+
+        arr.truncate_and_shrink(0);
+        assert_eq!(arr.allocated_segments, 0);
+        assert!(arr.segment_ptr(0).is_null());
+    }
+
+    #[test]
+    fn test_reserve_contiguous_after_regular_growth() {
+        let mut arr: SegArray<String> = SegArray::new();
+        for i in 0..5 {
+            arr.append(i.to_string());
+        }
+        assert_eq!(arr.allocated_segments, 3);
+
+        arr.reserve_contiguous(50);
+        assert_eq!(arr.allocated_segments, 6);
+        assert_eq!(arr.segment_ptr(4), unsafe { arr.segment_ptr(3).add(8) });
+        assert_eq!(arr.segment_ptr(5), unsafe { arr.segment_ptr(3).add(24) });
+
+        for i in 5..40 {
+            arr.append(i.to_string());
+        }
+        arr.truncate(20);
+        arr.shrink_to_fit();
+        assert_eq!(arr.allocated_segments, 6);
+
+        // Once nothing lives in the shared buffer it's released as a whole
+        arr.truncate_and_shrink(6);
+        assert_eq!(arr.allocated_segments, 3);
+        assert_eq!(arr[5], "5");
+
+        // Regular growth picks up again after the freed buffer
+        arr.reserve(5);
+        assert_eq!(arr.allocated_segments, 4);
+    }
+
+    #[test]
+    fn test_as_vec_refs() {
+        let empty: SegArray<String> = SegArray::new();
+        assert!(empty.as_vec_refs().is_empty());
+
+        let mut arr: SegArray<String> = SegArray::new();
+        for i in 0..9 {
+            arr.append(i.to_string());
+        }
+        let refs = arr.as_vec_refs();
+        assert_eq!(refs.len(), 9);
+        for (i, item) in refs.iter().enumerate() {
+            assert!(std::ptr::eq(*item, &arr[i]));
+        }
+    }
+
+    #[test]
+    fn test_pop_if() {
+        let mut arr = SegArray::from([1, 2, 3, 4]);
+        assert_eq!(arr.pop_if(|x| *x % 2 == 0), Some(4));
+        assert_eq!(arr.pop_if(|x| *x % 2 == 0), None);
+        assert_eq!(arr.len(), 3);
+        assert_eq!(arr.last(), Some(&3));
+
+        while arr.pop_if(|_| true).is_some() {}
+        assert!(arr.is_empty());
+        assert_eq!(arr.pop_if(|_| true), None);
+    }
+
+    #[test]
+    fn test_eq_array() {
+        let arr = SegArray::from([1, 2, 3, 4, 5]);
+        assert_eq!(arr, [1, 2, 3, 4, 5]);
+        assert_eq!([1, 2, 3, 4, 5], arr);
+        assert_ne!(arr, [1, 2, 3, 4, 6]);
+        assert_ne!(arr, [1, 2, 3, 4]);
+        assert_ne!([1, 2, 3, 4, 5, 6], arr);
+
+        let empty: SegArray<i32> = SegArray::new();
+        assert_eq!(empty, []);
+    }
+
+    #[test]
+    fn test_copy_from_slice() {
+        let mut arr: SegArray<u16> = SegArray::new();
+        for _ in 0..20 {
+            arr.append(0);
+        }
+        let src: Vec<u16> = (100..120).collect();
+        arr.copy_from_slice(&src);
+        assert_eq!(arr.copy_to_vec(), src);
+
+        let mut empty: SegArray<u16> = SegArray::new();
+        empty.copy_from_slice(&[]);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Source slice length 2 does not match array length 3")]
+    fn test_copy_from_slice_length_mismatch() {
+        let mut arr = SegArray::from([1, 2, 3]);
+        arr.copy_from_slice(&[1, 2]);
+    }
+
+    #[test]
+    fn test_segment_slices_mut() {
+        let mut arr: SegArray<u64> = SegArray::new();
+        assert!(arr.segment_slices_mut().is_empty());
+        for i in 0..50 {
+            arr.append(i);
+        }
+
+        let slices = arr.segment_slices_mut();
+        assert_eq!(
+            slices.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            vec![1, 2, 4, 8, 16, 19]
+        );
+        std::thread::scope(|scope| {
+            for slice in slices {
+                scope.spawn(move || slice.iter_mut().for_each(|x| *x *= 2));
+            }
+        });
+
+        assert_eq!(
+            arr.copy_to_vec(),
+            (0..50).map(|x| x * 2).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_iter_enumerated() {
+        let mut arr: SegArray<char> = SegArray::new();
+        for c in 'a'..='j' {
+            arr.append(c);
+        }
+        let pairs: Vec<(usize, char)> = arr.iter_enumerated().map(|(i, &c)| (i, c)).collect();
+        assert_eq!(pairs, ('a'..='j').enumerate().collect::<Vec<_>>());
+
+        for (i, item) in arr.iter_enumerated() {
+            assert!(std::ptr::eq(item, &arr[i]));
+        }
+        assert_eq!(SegArray::<char>::new().iter_enumerated().next(), None);
+    }
+
+    #[test]
+    fn test_try_get() {
+        let mut arr = SegArray::from([10, 20, 30]);
+        assert_eq!(arr.try_get(2), Ok(&30));
+        assert_eq!(
+            arr.try_get(3),
+            Err(SegArrayError::IndexOutOfBounds { index: 3, len: 3 })
+        );
+
+        *arr.try_get_mut(0).unwrap() = 11;
+        assert_eq!(arr[0], 11);
+        let err = arr.try_get_mut(7).unwrap_err();
+        assert_eq!(err.to_string(), "index 7 is out of bounds for length 3");
+    }
+
+    #[test]
+    fn test_repeat() {
+        let arr = SegArray::from([1, 2, 3]);
+        let tiled = arr.repeat(4);
+        assert_eq!(tiled, [1, 2, 3, 1, 2, 3, 1, 2, 3, 1, 2, 3]);
+        assert_eq!(tiled.capacity(), 15);
+
+        assert!(arr.repeat(0).is_empty());
+        assert_eq!(arr.repeat(1), [1, 2, 3]);
+        assert!(SegArray::<i32>::new().repeat(10).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to grow: CapacityOverflow")]
+    fn test_repeat_overflow() {
+        let arr = SegArray::from([1, 2]);
+        let _ = arr.repeat(usize::MAX);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_invariants_hold_across_operations() {
+        let mut arr: SegArray<String> = SegArray::new();
+        arr.assert_invariants();
+
+        for i in 0..40 {
+            arr.append(i.to_string());
+            arr.assert_invariants();
+        }
+        arr.pop();
+        arr.assert_invariants();
+        arr.truncate(17);
+        arr.assert_invariants();
+        arr.extend_from_within(3..9);
+        arr.assert_invariants();
+        let _ = arr.extract_if(|s| s.ends_with('1')).count();
+        arr.assert_invariants();
+        arr.shrink_to(20);
+        arr.assert_invariants();
+        arr.truncate(4);
+        arr.defragment();
+        arr.assert_invariants();
+        arr.reserve_contiguous(100);
+        arr.assert_invariants();
+        arr.truncate_and_shrink(2);
+        arr.assert_invariants();
+
+        let mut other = SegArray::from(["a".to_string(), "b".to_string()]);
+        arr.append_all(&mut other);
+        arr.assert_invariants();
+        other.assert_invariants();
+        arr.clear();
+        arr.assert_invariants();
+
+        SegArray::<u8>::with_segments(4).assert_invariants();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Segment usage doesn't add up to the length")]
+    fn test_invariants_catch_corruption() {
+        // Dropping the corrupted array would panic again while unwinding, so leak it instead
+        let mut arr = std::mem::ManuallyDrop::new(SegArray::from([1, 2, 3, 4]));
+        arr.segment_usage[2] = 0;
+        arr.assert_invariants();
+    }
+
+    #[test]
+    fn test_into_iter_as_slice() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut iter = logged_array(6, &log).into_iter();
+        let ids = |iter: &SegArrayIntoIter<DropLogger>| {
+            iter.as_slice().iter().map(|x| x.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&iter), vec![0, 1, 2, 3, 4, 5]);
+
+        let first = iter.next().unwrap();
+        let second = iter.next().unwrap();
+        assert_eq!(ids(&iter), vec![2, 3, 4, 5]);
+        assert_eq!(iter.next().map(|x| x.id), Some(2));
+        assert_eq!(ids(&iter), vec![3, 4, 5]);
+
+        drop(iter);
+        let mut dropped = log.borrow().clone();
+        dropped.sort_unstable();
+        assert_eq!(dropped, vec![2, 3, 4, 5]);
+        assert_eq!((first.id, second.id), (0, 1));
+    }
+
+    #[test]
+    fn test_first_and_last_chunk() {
+        let mut arr: SegArray<u8> = SegArray::new();
+        arr.extend_from_slice(b"HDR:payload:END");
+
+        assert_eq!(arr.first_chunk::<4>(), Some([&b'H', &b'D', &b'R', &b':']));
+        assert_eq!(arr.last_chunk::<3>(), Some([&b'E', &b'N', &b'D']));
+        assert_eq!(arr.first_chunk::<0>(), Some([]));
+        assert!(arr.first_chunk::<15>().is_some());
+        assert_eq!(arr.first_chunk::<16>(), None);
+        assert_eq!(arr.last_chunk::<16>(), None);
+    }
+
+    #[test]
+    fn test_defragment_reuses_segments() {
+        let mut arr: SegArray<u32> = SegArray::new();
+        for i in 0..100 {
+            arr.append(i);
+        }
+        let pointers: Vec<*const u32> = (0..7).map(|i| arr.segment_ptr(i)).collect();
+
+        arr.truncate(10);
+        arr.defragment();
+        assert_eq!(arr.allocated_segments, 4);
+        assert_eq!(arr.capacity(), 15);
+        assert!(arr.segment_ptr(4).is_null());
+        assert_eq!(arr.copy_to_vec(), (0..10).collect::<Vec<_>>());
+
+        // Growing again hands back the pooled buffers rather than fresh ones
+        for i in 10..40 {
+            arr.append(i);
+        }
+        assert_eq!(arr.allocated_segments, 6);
+        for (i, &ptr) in pointers.iter().enumerate().take(6) {
+            assert_eq!(arr.segment_ptr(i), ptr);
+        }
+        arr.reserve(60);
+        assert_eq!(arr.segment_ptr(6), pointers[6]);
+        assert_eq!(arr.copy_to_vec(), (0..40).collect::<Vec<_>>());
+
+        arr.truncate(1);
+        arr.defragment();
+        arr.shrink_to_fit();
+        assert_eq!(arr.allocated_segments, 1);
+        assert!(arr.free_segments.iter().all(|seg| seg.is_null()));
+    }
+
+    #[test]
+    fn test_defragment_keeps_shared_allocations_together() {
+        let mut arr: SegArray<u32> = SegArray::new();
+        arr.append(0);
+        arr.reserve_contiguous(30);
+        assert_eq!(arr.allocated_segments, 5);
+        let shared = arr.segment_ptr(1);
+
+        // Segments 1..5 share a buffer that still holds elements, so nothing can be pooled
+        for i in 1..4 {
+            arr.append(i);
+        }
+        arr.defragment();
+        assert_eq!(arr.allocated_segments, 5);
+
+        arr.truncate(1);
+        arr.defragment();
+        assert_eq!(arr.allocated_segments, 1);
+
+        // Reusing any part of the pooled buffer brings back all of it
+        arr.append(1);
+        assert_eq!(arr.allocated_segments, 5);
+        assert_eq!(arr.segment_ptr(1), shared);
+        assert_eq!(arr, [0, 1]);
+    }
+
+    // Reports a fixed length no matter how many items it actually yields
+    struct MisreportingIter {
+        next: u32,
+        end: u32,
+        reported_len: usize,
+    }
+
+    impl Iterator for MisreportingIter {
+        type Item = u32;
+
+        fn next(&mut self) -> Option<u32> {
+            if self.next == self.end {
+                return None;
+            }
+            self.next += 1;
+            Some(self.next - 1)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (self.reported_len, Some(self.reported_len))
+        }
+    }
+
+    impl ExactSizeIterator for MisreportingIter {}
+
+    #[test]
+    fn test_extend_exact() {
+        let mut arr = SegArray::from([0u32, 1]);
+        arr.extend_exact(2..20);
+        assert_eq!(arr.len(), 20);
+        assert_eq!(arr.allocated_segments, 5);
+        assert_eq!(arr.copy_to_vec(), (0..20).collect::<Vec<_>>());
+
+        arr.extend_exact(std::iter::empty());
+        assert_eq!(arr.len(), 20);
+    }
+
+    #[test]
+    fn test_extend_exact_with_misreported_len() {
+        let mut arr: SegArray<u32> = SegArray::new();
+        arr.extend_exact(MisreportingIter {
+            next: 0,
+            end: 40,
+            reported_len: 5,
+        });
+        assert_eq!(arr.copy_to_vec(), (0..40).collect::<Vec<_>>());
+
+        arr.extend_exact(MisreportingIter {
+            next: 40,
+            end: 42,
+            reported_len: 100,
+        });
+        assert_eq!(arr.len(), 42);
+        assert_eq!(arr.last(), Some(&41));
+    }
+
+    #[test]
+    fn test_get_mut_or_append() {
+        let mut arr: SegArray<i32> = SegArray::new();
+        *arr.get_mut_or_append(2, || -1) = 2;
+        assert_eq!(arr, [-1, -1, 2]);
+
+        // Existing elements are returned untouched
+        assert_eq!(*arr.get_mut_or_append(0, || 100), -1);
+        assert_eq!(arr.len(), 3);
+
+        // A large gap spans several new segments
+        *arr.get_mut_or_append(300, || 0) = 300;
+        assert_eq!(arr.len(), 301);
+        assert_eq!(arr[300], 300);
+        assert_eq!(arr[150], 0);
+        assert_eq!(arr.allocated_segments, 9);
+    }
+
+    #[test]
+    fn test_as_view() {
+        fn sum<'a>(values: impl Into<SegSliceView<'a, i32>>) -> i32 {
+            values.into().iter().sum()
+        }
+
+        let arr = SegArray::from([1, 2, 3, 4, 5, 6]);
+        let view = arr.as_view();
+        assert_eq!(view.len(), 6);
+        assert_eq!(view.first(), Some(&1));
+        assert_eq!(view.last(), Some(&6));
+
+        assert_eq!(sum(&arr), 21);
+        assert_eq!(sum(view), 21);
+        assert_eq!(sum(arr.slice(2..4)), 7);
+
+        let empty: SegArray<i32> = SegArray::new();
+        assert!(empty.as_view().is_empty());
+    }
+
+    #[test]
+    fn test_take() {
+        let mut arr = SegArray::from(["a".to_string(), "b".to_string(), "c".to_string()]);
+        let first = &arr[0] as *const String;
+
+        let taken = arr.take();
+        assert_eq!(taken.len(), 3);
+        assert!(std::ptr::eq(&taken[0], first));
+        assert!(arr.is_empty());
+        assert_eq!(arr.capacity(), 0);
+
+        arr.append("d".to_string());
+        assert_eq!(arr, ["d".to_string()]);
+    }
+
+    #[test]
+    fn test_zip() {
+        let mut numbers: SegArray<i32> = SegArray::new();
+        for i in 0..20 {
+            numbers.append(i);
+        }
+        let mut labels: SegArray<String> = SegArray::new();
+        for i in 0..12 {
+            labels.append(format!("#{i}"));
+        }
+
+        let pairs: Vec<(i32, String)> =
+            numbers.zip(&labels).map(|(n, l)| (*n, l.clone())).collect();
+        assert_eq!(pairs.len(), 12);
+        assert_eq!(pairs[0], (0, "#0".to_string()));
+        assert_eq!(pairs[11], (11, "#11".to_string()));
+        assert_eq!(labels.zip(&numbers).count(), 12);
+        assert_eq!(numbers.zip(&SegArray::<u8>::new()).count(), 0);
+    }
+
+    #[test]
+    fn test_raw_parts_round_trip() {
+        let mut arr: SegArray<String> = SegArray::new();
+        for i in 0..11 {
+            arr.append(i.to_string());
+        }
+        let first = &arr[0] as *const String;
 
-        assert_eq!(arr.len(), 2);
-        assert_eq!(arr[0], "hello".to_string());
-        assert_eq!(arr[1], "world".to_string());
+        let (segments, segment_usage, allocated_segments, count) = arr.into_raw_parts();
+        assert_eq!(allocated_segments, 4);
+        assert_eq!(count, 11);
+        assert_eq!(&segment_usage[..5], &[1, 2, 4, 4, 0]);
+        assert!(segments[4].is_null());
 
-        assert_eq!(arr.pop(), Some("world".to_string()));
-        assert_eq!(arr.pop(), Some("hello".to_string()));
-        assert_eq!(arr.pop(), None);
+        let mut arr =
+            unsafe { SegArray::from_raw_parts(segments, segment_usage, allocated_segments, count) };
+        assert!(std::ptr::eq(&arr[0], first));
+        assert_eq!(arr[10], "10");
+        arr.append("11".to_string());
+        assert_eq!(arr.len(), 12);
     }
 
     #[test]
-    #[should_panic(expected = "Index out of bounds: index 2 is not less than length 2")]
-    fn test_out_of_bounds_panic() {
+    fn test_from_raw_parts_with_foreign_buffers() {
+        let mut segments = [std::ptr::null_mut(); MAX_SEGMENTS];
+        let mut segment_usage = [0; MAX_SEGMENTS];
+        for (i, seg) in segments.iter_mut().enumerate().take(2) {
+            let layout = Layout::array::<u64>(1 << i).unwrap();
+            *seg = unsafe { std::alloc::alloc(layout) as *mut u64 };
+        }
+        unsafe {
+            segments[0].write(7);
+            segments[1].write(8);
+        }
+        segment_usage[0] = 1;
+        segment_usage[1] = 1;
+
+        let arr = unsafe { SegArray::from_raw_parts(segments, segment_usage, 2, 2) };
+        assert_eq!(arr, [7, 8]);
+        assert_eq!(arr.capacity(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot disassemble a SegArray whose segments share an allocation")]
+    fn test_into_raw_parts_rejects_shared_allocations() {
+        let mut arr: SegArray<u8> = SegArray::new();
+        arr.reserve_contiguous(10);
+        let _ = arr.into_raw_parts();
+    }
+
+    #[test]
+    fn test_insert() {
         let mut arr: SegArray<i32> = SegArray::new();
-        arr.append(10);
-        arr.append(20);
+        arr.insert(0, 1);
+        arr.insert(0, 0);
+        arr.insert(2, 3);
+        arr.insert(2, 2);
+        assert_eq!(arr, [0, 1, 2, 3]);
 
-        // This should panic
-        let _ = arr[2];
+        for i in 4..20 {
+            arr.append(i);
+        }
+        // Shifts the tail across several segment boundaries
+        arr.insert(1, 100);
+        assert_eq!(arr.len(), 21);
+        assert_eq!(arr[0], 0);
+        assert_eq!(arr[1], 100);
+        assert_eq!(
+            arr.iter().skip(2).copied().collect::<Vec<_>>(),
+            (1..20).collect::<Vec<_>>()
+        );
     }
 
     #[test]
-    fn test_internal_indexing_helpers() {
-        // segment_index(index) -> (index + 1).ilog2()
-        assert_eq!(SegArray::<i32>::segment_index(0), 0); // 1.ilog2() -> 0
-        assert_eq!(SegArray::<i32>::segment_index(1), 1); // 2.ilog2() -> 1
-        assert_eq!(SegArray::<i32>::segment_index(2), 1); // 3.ilog2() -> 1
-        assert_eq!(SegArray::<i32>::segment_index(3), 2); // 4.ilog2() -> 2
-        assert_eq!(SegArray::<i32>::segment_index(6), 2); // 7.ilog2() -> 2
-        assert_eq!(SegArray::<i32>::segment_index(7), 3); // 8.ilog2() -> 3
+    #[should_panic(expected = "Insertion index 4 is greater than length 3")]
+    fn test_insert_out_of_bounds() {
+        let mut arr = SegArray::from([1, 2, 3]);
+        arr.insert(4, 4);
+    }
 
-        // segment_slot(index, seg_idx) -> index + 1 - (1 << seg_idx)
-        assert_eq!(SegArray::<i32>::segment_slot(0, 0), 0); // 0+1 - 2^0 = 0
-        assert_eq!(SegArray::<i32>::segment_slot(1, 1), 0); // 1+1 - 2^1 = 0
-        assert_eq!(SegArray::<i32>::segment_slot(2, 1), 1); // 2+1 - 2^1 = 1
-        assert_eq!(SegArray::<i32>::segment_slot(3, 2), 0); // 3+1 - 2^2 = 0
-        assert_eq!(SegArray::<i32>::segment_slot(6, 2), 3); // 6+1 - 2^2 = 3
-        assert_eq!(SegArray::<i32>::segment_slot(7, 3), 0); // 7+1 - 2^3 = 0
+    #[test]
+    fn test_insert_sorted() {
+        let mut arr: SegArray<(i32, &str)> = SegArray::new();
+        assert_eq!(arr.insert_sorted((5, "a")), 0);
+        assert_eq!(arr.insert_sorted((1, "a")), 0);
+        assert_eq!(arr.insert_sorted((9, "a")), 2);
+        assert_eq!(arr.insert_sorted((5, "b")), 2);
+        assert_eq!(arr, [(1, "a"), (5, "a"), (5, "b"), (9, "a")]);
+
+        let mut numbers: SegArray<i32> = SegArray::new();
+        for x in [7, 3, 3, 12, 0, 5, 3, 20, 1, 8, 8, 2] {
+            numbers.insert_sorted(x);
+        }
+        assert_eq!(numbers, [0, 1, 2, 3, 3, 3, 5, 7, 8, 8, 12, 20]);
+        assert_eq!(numbers.partition_point(|&x| x < 3), 3);
+        assert_eq!(numbers.partition_point(|&x| x <= 3), 6);
+    }
+
+    #[test]
+    fn test_spare_capacity_and_set_len() {
+        use std::io::Read;
+
+        let mut arr: SegArray<u8> = SegArray::new();
+        arr.extend_from_slice(b"abcd");
+        arr.reserve(20);
+        assert_eq!(arr.capacity(), 31);
+
+        let spare = arr.spare_capacity_segments_mut();
+        // Segment 2 has 3 free slots, then segments 3 and 4 are empty
+        assert_eq!(
+            spare.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            vec![3, 8, 16]
+        );
+
+        // Read straight into the spare slots, one contiguous run at a time
+        let mut source: &[u8] = b"efghijklmnop";
+        let mut written = 0;
+        for run in spare {
+            let mut buf = vec![0; run.len()];
+            let n = source.read(&mut buf).unwrap();
+            for (slot, &byte) in run.iter_mut().zip(&buf[..n]) {
+                slot.write(byte);
+            }
+            written += n;
+        }
+        unsafe { arr.set_len(4 + written) };
+        assert_eq!(arr.copy_to_vec(), b"abcdefghijklmnop");
+        assert_eq!(arr.segment_len(3), 8);
+        assert_eq!(arr.segment_len(4), 1);
+
+        arr.append(b'q');
+        assert_eq!(arr.last(), Some(&b'q'));
+
+        let mut empty: SegArray<u8> = SegArray::new();
+        assert!(empty.spare_capacity_segments_mut().is_empty());
+    }
+
+    #[test]
+    fn test_bytes_fold() {
+        let mut arr: SegArray<u32> = SegArray::new();
+        for i in 0..1_000_000 {
+            arr.append(i);
+        }
+
+        let mut calls = 0;
+        let sum = arr.bytes_fold(0u64, |acc, run| {
+            calls += 1;
+            acc + run.iter().map(|&x| u64::from(x)).sum::<u64>()
+        });
+        let naive: u64 = (0..arr.len()).map(|i| u64::from(arr[i])).sum();
+        assert_eq!(sum, naive);
+        assert_eq!(calls, 20);
+
+        let empty: SegArray<u32> = SegArray::new();
+        assert_eq!(empty.bytes_fold(1, |acc, _| acc + 1), 1);
+    }
+
+    #[test]
+    fn test_get_rel() {
+        let arr = SegArray::from([10, 20, 30, 40]);
+        assert_eq!(arr.get_rel(0), Some(&10));
+        assert_eq!(arr.get_rel(3), Some(&40));
+        assert_eq!(arr.get_rel(4), None);
+        assert_eq!(arr.get_rel(-1), Some(&40));
+        assert_eq!(arr.get_rel(-4), Some(&10));
+        assert_eq!(arr.get_rel(-5), None);
+        assert_eq!(arr.get_rel(isize::MIN), None);
+
+        let empty: SegArray<i32> = SegArray::new();
+        assert_eq!(empty.get_rel(-1), None);
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let mut arena: SegArena<String> = SegArena::new();
+        for name in ["a", "b", "c", "d", "e"] {
+            arena.append_reuse(name.to_string());
+        }
+
+        assert_eq!(arena.remove_tombstone(1), Some("b".to_string()));
+        assert_eq!(arena.remove_tombstone(3), Some("d".to_string()));
+        assert_eq!(arena.remove_tombstone(3), None);
+        assert_eq!(arena.remove_tombstone(10), None);
+
+        // Other indices are unaffected
+        assert_eq!(arena.len(), 5);
+        assert_eq!(arena.get(4).map(String::as_str), Some("e"));
+        assert_eq!(arena.get(1), None);
+        assert_eq!(arena.slots()[3], None);
+
+        assert_eq!(arena.append_reuse("x".to_string()), 1);
+        assert_eq!(arena.append_reuse("y".to_string()), 3);
+        assert_eq!(arena.append_reuse("z".to_string()), 5);
+        assert_eq!(arena.get(1).map(String::as_str), Some("x"));
+        assert_eq!(arena.len(), 6);
+    }
+
+    #[test]
+    fn test_append_reuse_fills_each_slot_once() {
+        let mut arena: SegArena<u32> = SegArena::new();
+        for i in 0..10 {
+            arena.append_reuse(i);
+        }
+        arena.remove_tombstone(2);
+        arena.remove_tombstone(5);
+        arena.remove_tombstone(8);
+        // Removing an already vacated slot doesn't queue it twice
+        arena.remove_tombstone(5);
+        *arena.get_mut(3).unwrap() = 30;
+        assert_eq!(arena.append_reuse(20), 2);
+        assert_eq!(arena.append_reuse(50), 5);
+        assert_eq!(arena.append_reuse(80), 8);
+        assert_eq!(arena.append_reuse(100), 10);
+        assert_eq!(arena.get(3), Some(&30));
+
+        // Vacancies carry over to clones
+        arena.remove_tombstone(3);
+        let mut copy = arena.clone();
+        assert_eq!(copy.append_reuse(31), 3);
+        assert_eq!(arena.append_reuse(32), 3);
+        assert_eq!(arena.append_reuse(110), 11);
+    }
+
+    #[test]
+    fn test_truncate_without_drop_glue() {
+        let mut arr: SegArray<u64> = SegArray::new();
+        for i in 0..100 {
+            arr.append(i);
+        }
+
+        arr.truncate(200);
+        assert_eq!(arr.len(), 100);
+        arr.truncate(37);
+        assert_eq!(arr.len(), 37);
+        assert_eq!(arr.segment_len(5), 6);
+        assert_eq!(arr.segment_len(6), 0);
+        assert_eq!(arr.last(), Some(&36));
+
+        arr.append(1000);
+        assert_eq!(arr[37], 1000);
+        arr.clear();
+        assert!(arr.is_empty());
+        assert_eq!(arr.segment_len(0), 0);
+    }
+
+    #[test]
+    fn test_into_boxed_slice() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let arr = logged_array(10, &log);
+        let boxed: Box<[DropLogger]> = arr.into();
+        assert!(log.borrow().is_empty());
+        let ids: Vec<usize> = boxed.iter().map(|d| d.id).collect();
+        assert_eq!(ids, (0..10).collect::<Vec<_>>());
+        drop(boxed);
+        assert_eq!(*log.borrow(), (0..10).collect::<Vec<_>>());
+
+        let empty: Box<[i32]> = SegArray::<i32>::new().into();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_enumerate_segments() {
+        let mut arr = SegArray::new();
+        for i in 0..10 {
+            arr.append(i);
+        }
+        let segments: Vec<(usize, &[i32])> = arr.enumerate_segments().collect();
+        assert_eq!(
+            segments,
+            vec![
+                (0, &[0][..]),
+                (1, &[1, 2][..]),
+                (2, &[3, 4, 5, 6][..]),
+                (3, &[7, 8, 9][..]),
+            ]
+        );
+        assert_eq!(SegArray::<i32>::new().enumerate_segments().count(), 0);
+    }
+
+    #[test]
+    fn test_collect_with_len() {
+        let arr = SegArray::collect_with_len(0..10, 10);
+        assert_eq!(arr.len(), 10);
+        assert_eq!(arr.capacity(), 15);
+        assert!(arr.iter().copied().eq(0..10));
+
+        // A short iterator gives a shorter array, a long one is cut off at `len`
+        let short = SegArray::collect_with_len(0..5, 10);
+        assert!(short.iter().copied().eq(0..5));
+        let mut long = 0..10;
+        let cut = SegArray::collect_with_len(&mut long, 4);
+        assert!(cut.iter().copied().eq(0..4));
+        assert_eq!(long.next(), Some(4));
+
+        assert!(SegArray::<i32>::collect_with_len(0..3, 0).is_empty());
+    }
+
+    #[test]
+    fn test_collect_with_len_panicking_iterator() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let iter = (0..10).map(|id| {
+            if id == 6 {
+                panic!("iterator failed");
+            }
+            DropLogger {
+                id,
+                log: Rc::clone(&log),
+            }
+        });
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            SegArray::collect_with_len(iter, 10)
+        }));
+        assert!(result.is_err());
+        // Everything written before the panic is dropped exactly once
+        assert_eq!(*log.borrow(), vec![5, 4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_starts_and_ends_with() {
+        let arr = SegArray::from(*b"HDR payload END");
+        assert!(arr.starts_with(b"HDR"));
+        assert!(arr.starts_with(b""));
+        assert!(arr.starts_with(b"HDR payload END"));
+        assert!(!arr.starts_with(b"HDX"));
+        assert!(arr.ends_with(b"END"));
+        assert!(arr.ends_with(b""));
+        assert!(!arr.ends_with(b"ENX"));
+        assert!(!arr.ends_with(b"xHDR payload END"));
+        assert!(!arr.starts_with(b"HDR payload END!"));
+
+        let empty = SegArray::<u8>::new();
+        assert!(empty.starts_with(b""));
+        assert!(empty.ends_with(b""));
+        assert!(!empty.ends_with(b"a"));
+    }
+
+    #[test]
+    fn test_replace_block() {
+        let mut arr = SegArray::from([0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        // Crosses from segment 1 into segment 3
+        arr.replace_block(2..8, &[20, 30, 40, 50, 60, 70]);
+        assert_eq!(arr, [0, 1, 20, 30, 40, 50, 60, 70, 8, 9]);
+        arr.replace_block(..=0, &[-1]);
+        arr.replace_block(5..5, &[]);
+        assert_eq!(arr, [-1, 1, 20, 30, 40, 50, 60, 70, 8, 9]);
+
+        let mut strings = SegArray::from(["a", "b", "c", "d"].map(String::from));
+        strings.replace_block(1.., &["x", "y", "z"].map(String::from));
+        assert_eq!(strings, ["a", "x", "y", "z"].map(String::from));
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut logged = logged_array(6, &log);
+        let replacement: Vec<DropLogger> = (10..13)
+            .map(|id| DropLogger {
+                id,
+                log: Rc::new(RefCell::new(Vec::new())),
+            })
+            .collect();
+        logged.replace_block(2..5, &replacement);
+        // The old values are dropped as they're overwritten
+        assert_eq!(*log.borrow(), vec![2, 3, 4]);
+        let ids: Vec<usize> = logged.iter().map(|d| d.id).collect();
+        assert_eq!(ids, vec![0, 1, 10, 11, 12, 5]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Source slice length 2 does not match range length 3")]
+    fn test_replace_block_length_mismatch() {
+        let mut arr = SegArray::from([1, 2, 3, 4]);
+        arr.replace_block(1..4, &[0, 0]);
+    }
+
+    // Shares a live-instance counter across clones, and panics on the `fail_at`th clone
+    struct PanickyClone {
+        live: Rc<RefCell<isize>>,
+        clones: Rc<RefCell<usize>>,
+        fail_at: usize,
+    }
+
+    impl PanickyClone {
+        fn new(live: &Rc<RefCell<isize>>, clones: &Rc<RefCell<usize>>, fail_at: usize) -> Self {
+            *live.borrow_mut() += 1;
+            Self {
+                live: Rc::clone(live),
+                clones: Rc::clone(clones),
+                fail_at,
+            }
+        }
+    }
+
+    impl Clone for PanickyClone {
+        fn clone(&self) -> Self {
+            *self.clones.borrow_mut() += 1;
+            if *self.clones.borrow() == self.fail_at {
+                panic!("clone failed");
+            }
+            Self::new(&self.live, &self.clones, self.fail_at)
+        }
+    }
+
+    impl Drop for PanickyClone {
+        fn drop(&mut self) {
+            *self.live.borrow_mut() -= 1;
+        }
+    }
+
+    #[test]
+    fn test_clone_panicking_partway() {
+        let live = Rc::new(RefCell::new(0));
+        let clones = Rc::new(RefCell::new(0));
+        let mut arr = SegArray::new();
+        for _ in 0..10 {
+            arr.append(PanickyClone::new(&live, &clones, 5));
+        }
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| arr.clone()));
+        assert!(result.is_err());
+        // The four finished clones were dropped, and none of the originals were touched
+        assert_eq!(*live.borrow(), 10);
+        assert_eq!(arr.len(), 10);
+        drop(arr);
+        assert_eq!(*live.borrow(), 0);
+    }
+
+    #[test]
+    fn test_extend_from_slice_panicking_partway() {
+        let live = Rc::new(RefCell::new(0));
+        let clones = Rc::new(RefCell::new(0));
+        let items: Vec<PanickyClone> = (0..10)
+            .map(|_| PanickyClone::new(&live, &clones, 5))
+            .collect();
+        let mut arr = SegArray::new();
+        arr.append(PanickyClone::new(&live, &clones, 5));
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            arr.extend_from_slice(&items)
+        }));
+        assert!(result.is_err());
+        // The clones that finished stay in the array
+        assert_eq!(arr.len(), 5);
+        assert_eq!(*live.borrow(), 15);
+        drop(arr);
+        drop(items);
+        assert_eq!(*live.borrow(), 0);
+    }
+
+    #[test]
+    fn test_clone_is_deep() {
+        let arr = SegArray::from([String::from("a"), String::from("b"), String::from("c")]);
+        let mut copy = arr.clone();
+        copy[1].push('!');
+        assert_eq!(arr, ["a", "b", "c"].map(String::from));
+        assert_eq!(copy, ["a", "b!", "c"].map(String::from));
+        assert_ne!(arr.segment_ptr(0), copy.segment_ptr(0));
+    }
+
+    #[test]
+    fn test_to_contiguous_reuses_scratch() {
+        let mut arr = SegArray::from([1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let mut scratch = Vec::new();
+        assert_eq!(
+            arr.to_contiguous(&mut scratch),
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9]
+        );
+        let buffer = scratch.as_ptr();
+
+        arr.truncate(4);
+        assert_eq!(arr.to_contiguous(&mut scratch), &[1, 2, 3, 4]);
+        assert_eq!(scratch.as_ptr(), buffer);
+        assert!(SegArray::new().to_contiguous(&mut scratch).is_empty());
+    }
+
+    #[test]
+    fn test_insert_slice() {
+        let mut arr = SegArray::new();
+        for i in 0..30 {
+            arr.append(i);
+        }
+        // Lands in segment 3 and pushes the tail across the boundaries into segments 4 and 5
+        arr.insert_slice(13, &[100, 101, 102, 103]);
+        let expected: Vec<i32> = (0..13).chain(100..104).chain(13..30).collect();
+        assert!(arr.iter().eq(expected.iter()));
+
+        arr.insert_slice(34, &[-1, -2]);
+        assert_eq!(arr.last_chunk(), Some([&29, &-1, &-2]));
+        arr.insert_slice(0, &[7]);
+        assert_eq!(arr.first(), Some(&7));
+        arr.insert_slice(5, &[]);
+        assert_eq!(arr.len(), 37);
+
+        let mut strings = SegArray::from(["a", "d"].map(String::from));
+        strings.insert_slice(1, &["b", "c"].map(String::from));
+        assert_eq!(strings, ["a", "b", "c", "d"].map(String::from));
+    }
+
+    #[test]
+    #[should_panic(expected = "Insertion index 4 is greater than length 3")]
+    fn test_insert_slice_out_of_bounds() {
+        let mut arr = SegArray::from([1, 2, 3]);
+        arr.insert_slice(4, &[0]);
+    }
+
+    // The smallest cap, so the doubling segments hold 8191 elements and every later one 4096
+    const CAP: usize = MIN_MAX_SEGMENT_SIZE;
+    const DOUBLING: usize = 2 * CAP - 1;
+
+    #[test]
+    fn test_capped_segment_size() {
+        let len = DOUBLING + 2 * CAP + 3;
+        let mut arr = SegArray::with_max_segment_size(CAP);
+        for i in 0..len as i32 {
+            arr.append(i);
+        }
+        let capacities: Vec<usize> = (0..16).map(|i| arr.segment_capacity(i)).collect();
+        let expected: Vec<usize> = (0..13).map(|i| 1 << i).chain([CAP; 3]).collect();
+        assert_eq!(capacities, expected);
+        // The doubling segments fill up first, then each further segment holds `CAP`
+        assert_eq!(arr.capacity(), DOUBLING + 3 * CAP);
+        assert_eq!(arr.segment_len(15), 3);
+        assert!(arr.iter().copied().eq(0..len as i32));
+        assert_eq!(arr[DOUBLING + 5], (DOUBLING + 5) as i32);
+        assert_eq!(
+            arr.index_of(&arr[DOUBLING + CAP + 1]),
+            Some(DOUBLING + CAP + 1)
+        );
+
+        arr.insert(10, -1);
+        arr.insert_slice(DOUBLING + 7, &[-2, -3, -4, -5, -6]);
+        assert_eq!(arr.len(), len + 6);
+        assert_eq!(arr.pop(), Some(len as i32 - 1));
+        arr.truncate(9);
+        arr.shrink_to_fit();
+        assert_eq!(arr.capacity(), 15);
+
+        let copy = arr.clone();
+        assert_eq!(copy.segment_capacity(14), CAP);
+        assert!(copy.iter().eq(arr.iter()));
+        let mut taken = arr.clone();
+        let _ = taken.take();
+        taken.reserve(DOUBLING + CAP);
+        assert_eq!(taken.segment_capacity(13), CAP);
+
+        let mut contiguous = SegArray::<u8>::with_max_segment_size(CAP);
+        contiguous.reserve_contiguous(DOUBLING + CAP + 1);
+        contiguous.extend_from_slice(&vec![1; DOUBLING + CAP + 1]);
+        assert_eq!(contiguous.capacity(), DOUBLING + 2 * CAP);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_capped_segment_size_invariants() {
+        let mut arr = SegArray::with_max_segment_size(CAP);
+        for i in 0..(DOUBLING + 2 * CAP + 3) as i32 {
+            arr.append(i);
+            arr.assert_invariants();
+        }
+        arr.insert_slice(DOUBLING + 7, &[-2, -3, -4, -5, -6]);
+        arr.assert_invariants();
+        arr.truncate(9);
+        arr.shrink_to_fit();
+        arr.assert_invariants();
+
+        let mut contiguous = SegArray::<u8>::with_max_segment_size(CAP);
+        contiguous.reserve_contiguous(DOUBLING + CAP + 1);
+        contiguous.extend_from_slice(&vec![1; DOUBLING + CAP + 1]);
+        contiguous.assert_invariants();
+    }
+
+    #[test]
+    fn test_capped_segment_size_limits_capacity() {
+        let mut arr = SegArray::<u8>::with_max_segment_size(CAP);
+        // 2^s * (33 - s) - 1 with s = 12: the 13 doubling segments, then 19 more of 4096
+        assert_eq!(DOUBLING + 19 * CAP, 86_015);
+        assert!(arr.try_reserve(86_015).is_ok());
+        assert_eq!(
+            arr.try_reserve(86_016),
+            Err(SegArrayError::CapacityOverflow)
+        );
+
+        let mut larger = SegArray::<u8>::with_max_segment_size(1 << 20);
+        assert!(larger.try_reserve(13_631_487).is_ok());
+        assert_eq!(
+            larger.try_reserve(13_631_488),
+            Err(SegArrayError::CapacityOverflow)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Maximum segment size 2048 is below the minimum of 4096")]
+    fn test_capped_segment_size_minimum() {
+        SegArray::<u8>::with_max_segment_size(2048);
+    }
+
+    #[test]
+    #[should_panic(expected = "Maximum segment size 6 is not a power of two")]
+    fn test_capped_segment_size_must_be_power_of_two() {
+        SegArray::<u8>::with_max_segment_size(6);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Index 40 resolves to segment 5, which isn't allocated")]
+    fn test_index_with_corrupted_length() {
+        let mut arr = ManuallyDrop::new(SegArray::from([1, 2, 3]));
+        arr.count = 100;
+        let _ = arr[40];
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "which isn't allocated")]
+    fn test_get_with_corrupted_segment() {
+        let mut arr = ManuallyDrop::new(SegArray::from([1, 2, 3]));
+        arr.segments[1] = std::ptr::null_mut();
+        let _ = arr.get(2);
+    }
+
+    #[test]
+    fn test_indices() {
+        let mut arr = SegArray::from([1, 2, 3, 4, 5]);
+        for i in arr.indices() {
+            arr[i] *= 10;
+        }
+        assert_eq!(arr, [10, 20, 30, 40, 50]);
+        assert_eq!(arr.indices(), 0..5);
+        assert!(SegArray::<u8>::new().indices().is_empty());
+    }
+
+    #[test]
+    fn test_reserve_lazy_defers_top_segment() {
+        let mut arr: SegArray<u32> = SegArray::new();
+        arr.reserve_lazy(20);
+        // Segments 0..4 hold 15 elements, segment 4 waits for the 16th
+        assert_eq!(arr.allocated_segments, 4);
+        for i in 0..15 {
+            arr.append(i);
+            assert_eq!(arr.allocated_segments, 4);
+            assert_eq!(arr.capacity(), 15);
+        }
+        arr.append(15);
+        assert_eq!(arr.allocated_segments, 5);
+        assert_eq!(arr.capacity(), 31);
+
+        // Staying inside already allocated segments allocates nothing
+        arr.reserve_lazy(10);
+        assert_eq!(arr.allocated_segments, 5);
+
+        // The top segment is left alone even when it's the only one needed
+        let mut small: SegArray<u32> = SegArray::new();
+        small.reserve_lazy(1);
+        assert_eq!(small.capacity(), 0);
+        small.append(7);
+        assert_eq!(small.capacity(), 1);
+
+        let mut eager: SegArray<u32> = SegArray::new();
+        eager.reserve(20);
+        assert_eq!(eager.capacity(), 31);
+    }
+
+    #[test]
+    fn test_merge_sorted() {
+        let merged = SegArray::from([1, 3, 5]).merge_sorted(SegArray::from([2, 4, 6]), Ord::cmp);
+        assert_eq!(merged, [1, 2, 3, 4, 5, 6]);
+
+        let left_empty = SegArray::new().merge_sorted(SegArray::from([1, 2]), Ord::cmp);
+        assert_eq!(left_empty, [1, 2]);
+        let right_empty = SegArray::from([1, 2]).merge_sorted(SegArray::new(), Ord::cmp);
+        assert_eq!(right_empty, [1, 2]);
+        let both_empty = SegArray::<i32>::new().merge_sorted(SegArray::new(), Ord::cmp);
+        assert!(both_empty.is_empty());
+
+        // Ties keep elements from `self` first, and nothing is cloned or dropped early
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let left = logged_array(4, &log);
+        let mut right = SegArray::new();
+        for id in [11, 12] {
+            right.append(DropLogger {
+                id,
+                log: Rc::clone(&log),
+            });
+        }
+        let merged = left.merge_sorted(right, |a, b| (a.id % 10).cmp(&(b.id % 10)));
+        assert!(log.borrow().is_empty());
+        let ids: Vec<usize> = merged.iter().map(|d| d.id).collect();
+        assert_eq!(ids, vec![0, 1, 11, 2, 12, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to grow: CapacityOverflow")]
+    fn test_reserve_lazy_overflow() {
+        let mut arr: SegArray<u8> = SegArray::new();
+        arr.append(0);
+        arr.reserve_lazy(MAX_CAPACITY);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_from_par_iter() {
+        use rayon::prelude::*;
+
+        let arr: SegArray<u64> = (0..10_000u64).into_par_iter().map(|i| i * 2).collect();
+        assert_eq!(arr.len(), 10_000);
+        assert!(arr.iter().copied().eq((0..10_000).map(|i| i * 2)));
+
+        let filtered: SegArray<u64> = (0..1000u64)
+            .into_par_iter()
+            .filter(|i| i % 3 == 0)
+            .collect();
+        assert!(filtered
+            .iter()
+            .copied()
+            .eq((0..1000).filter(|i| i % 3 == 0)));
+
+        let empty: SegArray<u64> = Vec::<u64>::new().into_par_iter().collect();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to grow: CapacityOverflow")]
+    fn test_get_mut_or_append_at_usize_max() {
+        let mut arr: SegArray<u8> = SegArray::new();
+        arr.get_mut_or_append(usize::MAX, || 0);
+    }
+
+    #[test]
+    fn test_segment_capacity_past_last_segment() {
+        let arr: SegArray<u8> = SegArray::new();
+        assert_eq!(
+            arr.segment_capacity(MAX_SEGMENTS - 1),
+            1 << (MAX_SEGMENTS - 1)
+        );
+        assert_eq!(arr.segment_capacity(MAX_SEGMENTS), 0);
+        assert_eq!(arr.segment_capacity(64), 0);
+        assert_eq!(arr.segment_capacity(usize::MAX), 0);
+        assert_eq!(
+            SegArray::<u8>::with_max_segment_size(CAP).segment_capacity(40),
+            0
+        );
+    }
+
+    #[test]
+    fn test_with_zero_segments() {
+        let arr: SegArray<u8> = SegArray::with_segments(0);
+        assert_eq!(arr.capacity(), 0);
+        assert_eq!(SegArray::<u8>::with_segments(3).capacity(), 7);
+    }
+
+    #[test]
+    fn test_repeat_empty_many_times() {
+        // Returns straight away rather than looping over the empty array `n` times
+        let arr: SegArray<i32> = SegArray::new();
+        assert!(arr.repeat(usize::MAX).is_empty());
+        let mut one = SegArray::new();
+        one.append(7);
+        assert!(one.repeat(0).is_empty());
+    }
+
+    #[test]
+    fn test_drop_continues_past_panicking_element() {
+        struct PanicOnDrop(DropLogger);
+
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                if self.0.id == 5 {
+                    panic!("dropping element 5");
+                }
+            }
+        }
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut arr = SegArray::new();
+        for id in 0..10 {
+            arr.append(PanicOnDrop(DropLogger {
+                id,
+                log: Rc::clone(&log),
+            }));
+        }
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(arr)));
+        assert!(result.is_err());
+        assert_eq!(*log.borrow(), vec![9, 8, 7, 6, 5, 4, 3, 2, 1, 0]);
     }
 }